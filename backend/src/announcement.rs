@@ -0,0 +1,37 @@
+use std::path::{Path, PathBuf};
+
+use log::warn;
+
+use crate::config::AnnouncementConfig;
+
+/// Path of the announcement `name` in the announcement directory
+///
+/// Only plain file names are accepted, so clients can't reach files elsewhere
+pub fn resolve(config: &AnnouncementConfig, name: &str) -> Option<PathBuf> {
+    if Path::new(name).file_name() != Some(name.as_ref()) {
+        return None;
+    }
+    let path = config.dir.join(name);
+    path.is_file().then_some(path)
+}
+
+/// File names in the announcement directory, sorted
+pub fn list(config: &AnnouncementConfig) -> Vec<String> {
+    let entries = match std::fs::read_dir(&config.dir) {
+        Ok(entries) => entries,
+        Err(error) => {
+            warn!(
+                "Failed to read announcement directory {}: {error}",
+                config.dir.display()
+            );
+            return Vec::new();
+        }
+    };
+    let mut names = entries
+        .flatten()
+        .filter(|entry| entry.path().is_file())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .collect::<Vec<_>>();
+    names.sort();
+    names
+}
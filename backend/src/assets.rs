@@ -0,0 +1,87 @@
+use std::path::{Component, Path, PathBuf};
+
+#[cfg(feature = "embed-frontend")]
+static FRONTEND: include_dir::Dir<'static> =
+    include_dir::include_dir!("$CARGO_MANIFEST_DIR/../frontend/dist");
+
+pub struct Asset {
+    pub body: Vec<u8>,
+    pub content_type: &'static str,
+}
+
+/// Look up a file of the frontend build
+///
+/// Files are read from `static_dir` when set, otherwise from the build embedded
+/// with the `embed-frontend` feature. Unknown paths without extension get
+/// `index.html`, so the frontend can handle them.
+pub async fn get(static_dir: Option<&Path>, path: &str) -> Option<Asset> {
+    let relative = sanitize(path)?;
+    if let Some(asset) = lookup(static_dir, &relative).await {
+        return Some(asset);
+    }
+    if relative.extension().is_none() {
+        lookup(static_dir, Path::new("index.html")).await
+    } else {
+        None
+    }
+}
+
+/// Relative path of the request, `None` if it tries to leave the directory
+fn sanitize(path: &str) -> Option<PathBuf> {
+    let mut relative = PathBuf::new();
+    for component in Path::new(path.trim_start_matches('/')).components() {
+        match component {
+            Component::Normal(name) => relative.push(name),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    if relative.as_os_str().is_empty() {
+        relative.push("index.html");
+    }
+    Some(relative)
+}
+
+async fn lookup(static_dir: Option<&Path>, relative: &Path) -> Option<Asset> {
+    let content_type = content_type(relative);
+    if let Some(static_dir) = static_dir {
+        let body = smol::fs::read(static_dir.join(relative)).await.ok()?;
+        return Some(Asset { body, content_type });
+    }
+    embedded(relative).map(|body| Asset { body, content_type })
+}
+
+#[cfg(feature = "embed-frontend")]
+fn embedded(relative: &Path) -> Option<Vec<u8>> {
+    FRONTEND
+        .get_file(relative)
+        .map(|file| file.contents().to_vec())
+}
+
+#[cfg(not(feature = "embed-frontend"))]
+fn embedded(_relative: &Path) -> Option<Vec<u8>> {
+    None
+}
+
+fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or_default();
+    match extension.to_ascii_lowercase().as_str() {
+        "html" => "text/html; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "json" | "map" => "application/json",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "txt" | "md" => "text/plain; charset=utf-8",
+        _ => "application/octet-stream",
+    }
+}
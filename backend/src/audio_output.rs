@@ -0,0 +1,100 @@
+//! Audio output selection, `vlc-rs` has no safe wrapper for these so libvlc is called directly
+
+use std::{
+    ffi::{CStr, CString, c_char},
+    ptr,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Audio output module (e.g. `pulse`, `alsa`) and device chosen by the admin
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AudioOutput {
+    pub module: String,
+    /// Default device of the module if not set
+    pub device: Option<String>,
+}
+
+/// An output device reported by libvlc
+#[derive(Debug, Clone, Serialize)]
+pub struct AudioDevice {
+    pub module: String,
+    pub module_description: String,
+    pub device: String,
+    pub description: String,
+}
+
+/// List every device of every audio output module
+///
+/// Creates its own VLC instance, so it can be called from any thread
+pub fn list_devices() -> Vec<AudioDevice> {
+    let Some(instance) = vlc::Instance::new() else {
+        return Vec::new();
+    };
+    let mut devices = Vec::new();
+    // SAFETY: the lists are walked until `p_next` is null and released once, the strings are copied before that
+    unsafe {
+        let outputs = vlc::sys::libvlc_audio_output_list_get(instance.raw());
+        let mut output = outputs;
+        while !output.is_null() {
+            let module_ptr = (*output).psz_name;
+            let module = to_string(module_ptr);
+            let module_description = to_string((*output).psz_description);
+
+            let list = vlc::sys::libvlc_audio_output_device_list_get(instance.raw(), module_ptr);
+            let mut device = list;
+            while !device.is_null() {
+                devices.push(AudioDevice {
+                    module: module.clone(),
+                    module_description: module_description.clone(),
+                    device: to_string((*device).psz_device),
+                    description: to_string((*device).psz_description),
+                });
+                device = (*device).p_next;
+            }
+            if !list.is_null() {
+                vlc::sys::libvlc_audio_output_device_list_release(list);
+            }
+            output = (*output).p_next;
+        }
+        if !outputs.is_null() {
+            vlc::sys::libvlc_audio_output_list_release(outputs);
+        }
+    }
+    devices
+}
+
+/// Switch `player` to `output`, the module change applies from the next song
+///
+/// Returns `false` if libvlc doesn't know the module
+pub fn apply(player: &vlc::MediaPlayer, output: &AudioOutput) -> bool {
+    let Ok(module) = CString::new(output.module.as_str()) else {
+        return false;
+    };
+    let device = output
+        .device
+        .as_deref()
+        .and_then(|device| CString::new(device).ok());
+    // SAFETY: the strings outlive the calls, libvlc copies them
+    unsafe {
+        if vlc::sys::libvlc_audio_output_set(player.raw(), module.as_ptr()) != 0 {
+            return false;
+        }
+        if let Some(device) = device {
+            vlc::sys::libvlc_audio_output_device_set(player.raw(), ptr::null(), device.as_ptr());
+        }
+    }
+    true
+}
+
+/// SAFETY: `ptr` is null or a valid C string
+unsafe fn to_string(ptr: *const c_char) -> String {
+    if ptr.is_null() {
+        String::new()
+    } else {
+        unsafe { CStr::from_ptr(ptr) }
+            .to_string_lossy()
+            .into_owned()
+    }
+}
@@ -0,0 +1,299 @@
+use std::{io::ErrorKind, path::PathBuf, time::Duration};
+
+use log::info;
+use serde::Deserialize;
+
+use chrono::NaiveDateTime;
+
+use crate::{
+    audio_output::AudioOutput,
+    scheduler::{ScheduleRule, TimeWindow},
+};
+
+const CONFIG_ENV: &str = "CAFE_MUSIC_CONFIG";
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Where the backend keeps its files (history, ...)
+    pub data_dir: PathBuf,
+    pub library: LibraryConfig,
+    pub ytdlp: YtdlpConfig,
+    pub queue: QueueConfig,
+    pub player: PlayerConfig,
+    pub http: HttpConfig,
+    pub admin: AdminConfig,
+    pub announcements: AnnouncementConfig,
+    pub tts: TtsConfig,
+    pub schedule: Vec<ScheduleRule>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            data_dir: PathBuf::from("data"),
+            library: LibraryConfig::default(),
+            ytdlp: YtdlpConfig::default(),
+            queue: QueueConfig::default(),
+            player: PlayerConfig::default(),
+            http: HttpConfig::default(),
+            admin: AdminConfig::default(),
+            announcements: AnnouncementConfig::default(),
+            tts: TtsConfig::default(),
+            schedule: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LibraryConfig {
+    /// Directories scanned (recursively) for local music files
+    pub dirs: Vec<PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct YtdlpConfig {
+    /// Seconds before a yt-dlp call is killed
+    pub timeout_secs: u64,
+}
+
+impl Default for YtdlpConfig {
+    fn default() -> Self {
+        YtdlpConfig { timeout_secs: 60 }
+    }
+}
+
+impl YtdlpConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct QueueConfig {
+    pub head_policy: HeadPolicy,
+    /// Fetches failing with a transient error are tried this many times in total
+    pub max_attempts: u32,
+    /// Seconds before the first retry, doubled on each further attempt
+    pub retry_delay_secs: u64,
+    /// Upper bound of the retry delay
+    pub max_retry_delay_secs: u64,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        QueueConfig {
+            head_policy: HeadPolicy::default(),
+            max_attempts: 5,
+            retry_delay_secs: 5,
+            max_retry_delay_secs: 300,
+        }
+    }
+}
+
+impl QueueConfig {
+    /// Delay before the next attempt, after `attempt` failed attempts
+    pub fn retry_delay(&self, attempt: u32) -> Duration {
+        let factor = 2u64.saturating_pow(attempt.saturating_sub(1));
+        Duration::from_secs(
+            self.retry_delay_secs
+                .saturating_mul(factor)
+                .min(self.max_retry_delay_secs),
+        )
+    }
+}
+
+/// What the player does when the first entry of the queue is not fetched yet
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HeadPolicy {
+    /// Wait for the first entry
+    #[default]
+    Wait,
+    /// Play the first fetched entry, the others stay in place
+    SkipAhead,
+    /// Move entries that are not fetched yet to the back of the queue
+    MoveBack,
+}
+
+impl HeadPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HeadPolicy::Wait => "wait",
+            HeadPolicy::SkipAhead => "skip_ahead",
+            HeadPolicy::MoveBack => "move_back",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PlayerConfig {
+    /// Audio codecs in order of preference, matched as prefix of yt-dlp's `acodec`
+    pub preferred_codecs: Vec<String>,
+    /// Formats above this bitrate (kbps) are only used when nothing else works
+    pub max_bitrate_kbps: Option<f32>,
+    /// Fall back to formats with video when no audio-only format works
+    pub allow_muxed: bool,
+    /// Audio output module and device, VLC's default if not set
+    pub audio_output: Option<AudioOutput>,
+    /// Length of the volume ramp when pausing, resuming and skipping
+    pub fade_ms: u64,
+    /// Songs fade out over their last `fade_end_ms`
+    pub fade_end_ms: u64,
+}
+
+impl Default for PlayerConfig {
+    fn default() -> Self {
+        PlayerConfig {
+            preferred_codecs: vec!["opus".to_owned(), "mp4a".to_owned()],
+            max_bitrate_kbps: None,
+            allow_muxed: true,
+            audio_output: None,
+            fade_ms: 500,
+            fade_end_ms: 3000,
+        }
+    }
+}
+
+impl PlayerConfig {
+    pub fn fade(&self) -> Duration {
+        Duration::from_millis(self.fade_ms)
+    }
+
+    pub fn fade_end(&self) -> Duration {
+        Duration::from_millis(self.fade_end_ms)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpConfig {
+    /// Address the HTTP and WebSocket server listens on
+    pub listen: String,
+    /// Serve the frontend from this directory (the `dist` of the Vite build)
+    pub static_dir: Option<PathBuf>,
+}
+
+impl Default for HttpConfig {
+    fn default() -> Self {
+        HttpConfig {
+            listen: "0.0.0.0:9001".to_owned(),
+            static_dir: None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    /// Token admins log in with, admin commands are disabled if not set
+    pub token: Option<String>,
+}
+
+impl AdminConfig {
+    /// Compared in constant time, so the token can't be guessed from response times
+    pub fn is_valid(&self, token: &str) -> bool {
+        self.token
+            .as_deref()
+            .is_some_and(|admin| constant_time_eq(admin.as_bytes(), token.as_bytes()))
+    }
+}
+
+/// Only the length can leak, every byte is compared
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let diff = a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b));
+    std::hint::black_box(diff) == 0
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AnnouncementConfig {
+    /// Audio files that can be announced, referred to by file name
+    pub dir: PathBuf,
+    /// Music volume during an announcement, relative to the normal volume
+    pub duck_volume: f32,
+}
+
+impl Default for AnnouncementConfig {
+    fn default() -> Self {
+        AnnouncementConfig {
+            dir: PathBuf::from("announcements"),
+            duck_volume: 0.2,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TtsConfig {
+    /// Speak the title of the next song before it starts
+    pub enabled: bool,
+    /// Program and arguments writing speech to a wav file, `{text}` and `{output}` are replaced.
+    /// The text is written to stdin when no argument contains `{text}`.
+    pub command: Vec<String>,
+    /// Seconds before the TTS program is killed
+    pub timeout_secs: u64,
+    /// Only speak within these windows, always if empty
+    pub windows: Vec<TimeWindow>,
+}
+
+impl Default for TtsConfig {
+    fn default() -> Self {
+        TtsConfig {
+            enabled: false,
+            command: ["espeak-ng", "-w", "{output}", "{text}"]
+                .map(str::to_owned)
+                .to_vec(),
+            timeout_secs: 10,
+            windows: Vec::new(),
+        }
+    }
+}
+
+impl TtsConfig {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout_secs)
+    }
+
+    /// Whether songs should be announced at `now`
+    pub fn is_active(&self, now: NaiveDateTime) -> bool {
+        self.enabled
+            && (self.windows.is_empty() || self.windows.iter().any(|window| window.is_active(now)))
+    }
+}
+
+impl Config {
+    /// Load config from the path in `CAFE_MUSIC_CONFIG`, or `config.toml`.
+    ///
+    /// A missing file is not an error, the defaults are used instead.
+    pub fn load() -> anyhow::Result<Config> {
+        let path = std::env::var_os(CONFIG_ENV)
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_CONFIG_PATH));
+
+        let content = match std::fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(error) if error.kind() == ErrorKind::NotFound => {
+                info!("Config file {} not found, using defaults", path.display());
+                return Ok(Config::default());
+            }
+            Err(error) => {
+                return Err(anyhow::anyhow!(
+                    "Failed to read config file {}: {error}",
+                    path.display()
+                ));
+            }
+        };
+
+        toml::from_str(&content)
+            .map_err(|error| anyhow::anyhow!("Invalid config file {}: {error}", path.display()))
+    }
+}
@@ -1,21 +1,433 @@
-use async_tungstenite::accept_async;
+use std::{net::IpAddr, path::Path};
+
+use async_tungstenite::WebSocketStream;
 use async_tungstenite::tungstenite::Message;
+use async_tungstenite::tungstenite::protocol::{CloseFrame, frame::coding::CloseCode};
 use futures::{SinkExt, StreamExt};
 use log::{info, warn};
-use serde_json::json;
+use serde_json::{Map, Value, json};
 use smol::{channel::Receiver, channel::Sender, future::try_zip, lock::Mutex, net::TcpStream};
 
-use crate::song_queue::QueueEntry;
+use crate::announcement;
+use crate::audio_output::{AudioOutput, list_devices};
+use crate::config::Config;
+use crate::history::unix_now;
+use crate::library::LocalTrack;
+use crate::playlist::{self, ImportItem};
+use crate::playlists::enqueue_songs;
+use crate::song_queue::{QueueEntry, QueueItem, Requester, SavedSong, Song, saved_songs};
+use crate::stats::{self, StatsQuery};
+use crate::users::{IdentifyError, Users};
 use crate::yt_dlp::{YoutubeInfo, get_ytdlp};
 use crate::{AppState, BroadcastEvent, HandlerEvent};
 
+const LIBRARY_SEARCH_LIMIT: usize = 100;
+
+fn info_json(info: &YoutubeInfo) -> Value {
+    json!({"fetched": true, "title": info.title, "url": info.webpage_url, "source": info.extractor_key, "time": info.duration})
+}
+
+fn local_json(track: &LocalTrack) -> Value {
+    json!({"fetched": true, "local": true, "source": "local", "title": track.title, "artist": track.artist, "time": track.duration})
+}
+
+pub fn song_json(song: &Song) -> Value {
+    match song {
+        Song::Youtube(info) => info_json(info),
+        Song::Local(track) => local_json(track),
+    }
+}
+
+/// Who requested a song, `null` if unknown
+fn requester_json(users: &Users, requester: Option<u64>) -> Value {
+    match requester {
+        Some(id) => json!({"id": id, "nickname": users.nickname(id)}),
+        None => Value::Null,
+    }
+}
+
+pub fn now_playing_json(state: &AppState) -> Option<Value> {
+    state.now_playing.as_ref().map(|now_playing| {
+        let mut song = song_json(&now_playing.song);
+        song["requester"] = requester_json(&state.users, now_playing.requester);
+        song["reactions"] = json!(now_playing.reactions.counts());
+        song["started_at"] = json!(now_playing.started_at);
+        // Clients count from `started_at` while playing, the position would change every message
+        if now_playing.started_at.is_none() {
+            song["position"] = json!(now_playing.position);
+        }
+        song
+    })
+}
+
+fn entry_json(item: &QueueItem, users: &Users) -> Value {
+    let mut entry = match &item.entry {
+        QueueEntry::Fetched(info) => info_json(info),
+        QueueEntry::Local(track) => local_json(track),
+        QueueEntry::Fetching(task) => json!({"fetched": false, "url": task.url()}),
+        QueueEntry::Refetching(task) => {
+            json!({"fetched": false, "url": task.url(), "title": task.title(), "source": task.source()})
+        }
+        QueueEntry::PendingRefetch(task) => {
+            json!({"fetched": false, "url": task.url(), "title": task.title(), "source": task.source()})
+        }
+        QueueEntry::RetryPending(task) => {
+            let failed = task.failed();
+            json!({"fetched": false, "url": failed.url(), "title": failed.title(), "source": failed.source(), "error": failed.error(), "attempt": failed.attempt(), "retry_in": task.retry_in().as_secs()})
+        }
+        QueueEntry::Failed(failed) => {
+            json!({"fetched": false, "url": failed.url(), "title": failed.title(), "source": failed.source(), "error": failed.error(), "attempt": failed.attempt()})
+        }
+    };
+    entry["id"] = json!(item.id);
+    entry["state"] = json!(item.entry.state());
+    entry["requester"] = requester_json(users, item.requester.user());
+    entry
+}
+
+/// Content of the `queue` message
+pub fn queue_json(state: &AppState, config: &Config) -> Value {
+    let now_playing = now_playing_json(state);
+    let head_policy = config.queue.head_policy;
+
+    // Clients work out ETAs from `play_order` and the entry lengths, ETAs in the entries would
+    // all change whenever one song is added. Songs of unknown length make the total inexact.
+    let entries = state.queue.iter().collect::<Vec<_>>();
+    let play_order = state.queue.play_order(head_policy);
+    let mut total_time = 0;
+    let mut total_exact = true;
+    for &index in &play_order {
+        match entries[index].entry.duration() {
+            Some(duration) => total_time += duration,
+            None => total_exact = false,
+        }
+    }
+    let queue = entries
+        .iter()
+        .map(|item| entry_json(item, &state.users))
+        .collect::<Vec<_>>();
+    json!({
+        "now_playing": now_playing,
+        "queue": queue,
+        "play_order": play_order.iter().map(|&index| entries[index].id).collect::<Vec<_>>(),
+        "total_time": total_time,
+        "total_exact": total_exact,
+        "head_policy": head_policy.as_str(),
+        "up_next": state.queue.next_ready_index(head_policy),
+    })
+}
+
+/// The `queue` message with the ETA of each entry, for `/api/queue`
+///
+/// Queue patches leave ETAs to the clients (see [`queue_json`]), here they are worked out at the
+/// time of the request: `starts_at` is a Unix timestamp counted from the `started_at` of the song
+/// playing (`null` while paused), `eta` the seconds from now. Failed entries have neither.
+pub fn queue_json_with_etas(state: &AppState, config: &Config) -> Value {
+    let mut msg = queue_json(state, config);
+    let now = unix_now();
+    // When the song playing ends, `None` while paused, and the seconds left until then
+    let (mut ends_at, mut left, mut exact) = (Some(now), 0, true);
+    if let Some(now_playing) = &state.now_playing {
+        let duration = now_playing.song.duration();
+        exact = duration.is_some();
+        let duration = u64::from(duration.unwrap_or_default());
+        match now_playing.started_at {
+            Some(started_at) => {
+                let end = (started_at + duration).max(now);
+                (ends_at, left) = (Some(end), end - now);
+            }
+            None => {
+                ends_at = None;
+                left = duration.saturating_sub(u64::from(now_playing.position));
+            }
+        }
+    }
+
+    let entries = state.queue.iter().collect::<Vec<_>>();
+    let mut etas = vec![None; entries.len()];
+    let mut offset = 0;
+    for index in state.queue.play_order(config.queue.head_policy) {
+        etas[index] = Some((offset, exact));
+        match entries[index].entry.duration() {
+            Some(duration) => offset += u64::from(duration),
+            None => exact = false,
+        }
+    }
+    if let Some(queue) = msg["queue"].as_array_mut() {
+        for (entry, eta) in queue.iter_mut().zip(etas) {
+            entry["eta"] = json!(eta.map(|(offset, _)| left + offset));
+            entry["eta_exact"] = json!(eta.map(|(_, exact)| exact));
+            entry["starts_at"] = json!(eta.and_then(|(offset, _)| Some(ends_at? + offset)));
+        }
+    }
+    msg
+}
+
+/// Content of the `player` message
+pub fn player_json(state: &AppState, config: &Config) -> Value {
+    json!({
+        "playing": state.player.playing,
+        "volume": state.player.volume(),
+        "requested_volume": state.player.requested_volume,
+        "audio_output": state.audio_output(config),
+        "schedule": state.player.schedule,
+    })
+}
+
+/// Audio devices libvlc knows about, and the one in use
+pub async fn audio_devices_json(state: &Mutex<AppState<'_>>, config: &Config) -> Value {
+    let devices = smol::unblock(list_devices).await;
+    let state = state.lock().await;
+    json!({
+        "devices": devices,
+        "selected": state.audio_output(config),
+    })
+}
+
+/// Select an audio output and save it, `None` goes back to the one in the config
+pub async fn set_audio_output(
+    state: &Mutex<AppState<'_>>,
+    handler_event_tx: &Sender<HandlerEvent>,
+    output: Option<AudioOutput>,
+) {
+    {
+        let mut state = state.lock().await;
+        state.settings.audio_output = output;
+        state.settings.save();
+    }
+    let _ = handler_event_tx.send(HandlerEvent::SetAudioOutput).await;
+}
+
+/// Play an announcement from the announcement directory over the music
+///
+/// Returns `false` if there is no such file
+pub async fn announce(
+    config: &Config,
+    handler_event_tx: &Sender<HandlerEvent>,
+    name: &str,
+) -> bool {
+    let Some(path) = announcement::resolve(&config.announcements, name) else {
+        return false;
+    };
+    let _ = handler_event_tx.send(HandlerEvent::Announce(path)).await;
+    true
+}
+
+/// Every user that said hello, for admins
+pub fn users_json(state: &AppState) -> Value {
+    json!({ "users": state.users.list() })
+}
+
+/// Ban or unban a user, a banned user's songs are removed from the queue
+///
+/// Returns `false` if there is no such user
+pub async fn set_banned(
+    state: &Mutex<AppState<'_>>,
+    handler_event_tx: &Sender<HandlerEvent>,
+    user: u64,
+    banned: bool,
+) -> bool {
+    {
+        let mut state = state.lock().await;
+        if !state.users.set_banned(user, banned) {
+            return false;
+        }
+        if !banned {
+            info!("Unbanned user {user}");
+            return true;
+        }
+        let removed = state.queue.remove_by_user(user);
+        info!("Banned user {user}, removed {removed} songs from the queue");
+    }
+    let _ = handler_event_tx.send(HandlerEvent::UpdateQueue).await;
+    true
+}
+
+/// Statistics over the play history, read from disk off the executor
+pub async fn stats_json(config: &Config, query: StatsQuery) -> std::io::Result<Value> {
+    let data_dir = config.data_dir.clone();
+    let stats = smol::unblock(move || stats::compute(&data_dir, query)).await?;
+    Ok(json!(stats))
+}
+
+/// Queue a link to be fetched by yt-dlp
+pub async fn enqueue_link(
+    state: &Mutex<AppState<'_>>,
+    config: &Config,
+    link: String,
+    requester: Requester,
+) {
+    info!("Received link (url: {})", link);
+    let future = get_ytdlp(link.clone(), config.ytdlp.timeout());
+    state.lock().await.queue.push_task(future, link, requester);
+}
+
+/// Enqueue the songs of a playlist, see [`playlist::parse`]
+///
+/// Returns how many songs were added, files that are not in the library are left out
+pub async fn import_playlist(
+    state: &Mutex<AppState<'_>>,
+    config: &Config,
+    content: &str,
+    requester: &Requester,
+) -> Result<usize, String> {
+    let items = playlist::parse(content)?;
+    let mut added = 0;
+    for item in items.into_iter().take(playlist::MAX_IMPORT) {
+        match item {
+            ImportItem::Link(url) => {
+                enqueue_link(state, config, url, requester.clone()).await;
+                added += 1;
+            }
+            ImportItem::File(path) => {
+                let mut state = state.lock().await;
+                match state.library.find_path(&path).cloned() {
+                    Some(track) => {
+                        state.queue.push_local(track, requester.clone());
+                        added += 1;
+                    }
+                    None => warn!("Imported file is not in the library: {}", path.display()),
+                }
+            }
+        }
+    }
+    info!("Imported {added} songs");
+    Ok(added)
+}
+
+/// Saved playlists, and the one used as fallback
+pub fn playlists_json(state: &AppState) -> Value {
+    json!({
+        "playlists": state.playlists.list(),
+        "fallback": state.playlists.fallback(),
+    })
+}
+
+/// Song described by a `playlist_add` command: `url`, `local` (library id) or `now_playing`
+fn song_to_add(state: &AppState, obj: &Map<String, Value>) -> Option<SavedSong> {
+    if let Some(url) = obj.get("url").and_then(Value::as_str) {
+        return Some(SavedSong::Link {
+            url: url.to_owned(),
+            title: obj.get("title").and_then(Value::as_str).map(str::to_owned),
+            source: None,
+            duration: None,
+        });
+    }
+    if let Some(id) = obj.get("local").and_then(Value::as_u64) {
+        return state
+            .library
+            .get(id as usize)
+            .cloned()
+            .map(SavedSong::Local);
+    }
+    if obj.get("now_playing").and_then(Value::as_bool) == Some(true) {
+        return state
+            .now_playing
+            .as_ref()
+            .map(|now_playing| now_playing.song.saved());
+    }
+    None
+}
+
+/// Apply one of the admin `playlist_*` commands, the error can be shown to the client
+pub async fn edit_playlist(
+    state: &Mutex<AppState<'_>>,
+    handler_event_tx: &Sender<HandlerEvent>,
+    command: &Map<String, Value>,
+) -> Result<(), &'static str> {
+    let msg = command
+        .get("msg")
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+    let id = command.get("id").and_then(Value::as_u64);
+    let name = command.get("name").and_then(Value::as_str);
+    {
+        let mut state = state.lock().await;
+        let state = &mut *state;
+        let result = match (msg, id, name) {
+            ("playlist_create", _, Some(name)) => state.playlists.create(name).map(drop),
+            ("playlist_rename", Some(id), Some(name)) => state.playlists.rename(id, name),
+            ("playlist_delete", Some(id), _) => state.playlists.delete(id),
+            ("playlist_add", Some(id), _) => {
+                let song = song_to_add(state, command).ok_or("Song not found")?;
+                state.playlists.add(id, song)
+            }
+            ("playlist_remove", Some(id), _) => {
+                let index = command.get("index").and_then(Value::as_u64);
+                let index = index.ok_or("Malformed playlist command")?;
+                state.playlists.remove(id, index as usize)
+            }
+            ("playlist_fallback", id, _) => state.playlists.set_fallback(id),
+            _ => return Err("Malformed playlist command"),
+        };
+        result.map_err(|error| error.user_message())?;
+    }
+    let _ = handler_event_tx.send(HandlerEvent::UpdatePlaylists).await;
+    Ok(())
+}
+
+/// Put every song of a saved playlist in the queue, returns how many were added
+pub async fn enqueue_playlist(
+    state: &Mutex<AppState<'_>>,
+    config: &Config,
+    handler_event_tx: &Sender<HandlerEvent>,
+    id: u64,
+    requester: &Requester,
+) -> Option<usize> {
+    let songs = state.lock().await.playlists.get(id)?.songs.clone();
+    let added = enqueue_songs(state, config, songs, requester).await;
+    let _ = handler_event_tx.send(HandlerEvent::UpdateQueue).await;
+    Some(added)
+}
+
+/// The song playing and the queue as a playlist
+///
+/// Without `full_paths`, local files are given relative to their library directory: where the
+/// library lives on the server is only for admins. Imports find them by the end of their path.
+pub fn export_playlist(
+    state: &AppState,
+    config: &Config,
+    format: playlist::Format,
+    full_paths: bool,
+) -> String {
+    let mut songs = saved_songs(state);
+    if !full_paths {
+        for song in &mut songs {
+            if let SavedSong::Local(track) = song {
+                let relative = config
+                    .library
+                    .dirs
+                    .iter()
+                    .find_map(|dir| track.path.strip_prefix(dir).ok())
+                    .or_else(|| track.path.file_name().map(Path::new))
+                    .map(Path::to_path_buf)
+                    .unwrap_or_default();
+                track.path = relative;
+            }
+        }
+    }
+    playlist::export(&songs, format)
+}
+
+/// Set the volume, capped by the active schedule rule
+pub async fn set_volume(
+    state: &Mutex<AppState<'_>>,
+    handler_event_tx: &Sender<HandlerEvent>,
+    volume: f32,
+) {
+    state.lock().await.player.requested_volume = volume;
+    let _ = handler_event_tx.send(HandlerEvent::SetVolume).await;
+}
+
 pub async fn handle(
-    stream: TcpStream,
+    websocket: WebSocketStream<TcpStream>,
+    addr: IpAddr,
     state: &Mutex<AppState<'_>>,
-    event_recv: Receiver<BroadcastEvent>,
+    config: &Config,
+    (event_tx, event_recv): (Sender<BroadcastEvent>, Receiver<BroadcastEvent>),
     handler_event_tx: Sender<HandlerEvent>,
 ) -> anyhow::Result<()> {
-    let websocket = accept_async(stream).await?;
+    let mut requester = Requester::new(event_tx);
 
     let (writer, mut reader) = websocket.split();
 
@@ -33,40 +445,54 @@ pub async fn handle(
     let task1 = async {
         while let Ok(broadcast_event) = event_recv.recv().await {
             let msg = match broadcast_event {
-                BroadcastEvent::UpdateQueue => {
+                BroadcastEvent::SendQueue => state.lock().await.queue_sync.snapshot(),
+                BroadcastEvent::Queue(msg) => {
+                    writer
+                        .lock()
+                        .await
+                        .send(Message::Text(msg.as_ref().into()))
+                        .await?;
+                    continue;
+                }
+                BroadcastEvent::UpdatePlayer => {
+                    let mut msg = player_json(&*state.lock().await, config);
+                    msg["msg"] = json!("player");
+                    msg
+                }
+                BroadcastEvent::UpdateReactions => {
                     let state = state.lock().await;
-                    let info_to_json = |info: &YoutubeInfo| {
-                        let url = format!("https://www.youtube.com/watch?v={}", info.id);
-                        json!({"fetched": true, "title": info.title, "url": url, "time": info.duration})
-                    };
-                    let entry_to_json = |entry: &QueueEntry| match entry {
-                        QueueEntry::Fetched(info) => info_to_json(info),
-                        QueueEntry::Fetching(task) => {
-                            json!({"fetched": false, "url": task.url()})
-                        }
-                        QueueEntry::Refetching(task) => {
-                            json!({"fetched": false, "url": task.url(), "title": task.title()})
-                        }
-                        QueueEntry::PendingRefetch(task) => {
-                            json!({"fetched": false, "url": task.url(), "title": task.title()})
-                        }
-                    };
-                    let now_playing = state.now_playing.as_ref().map(info_to_json);
-                    let queue = state.queue.iter().map(entry_to_json).collect::<Vec<_>>();
+                    let counts = state
+                        .now_playing
+                        .as_ref()
+                        .map(|now_playing| now_playing.reactions.counts());
                     json!({
-                        "msg": "queue",
-                        "now_playing": now_playing,
-                        "queue": queue,
+                        "msg": "reactions",
+                        "reactions": counts,
                     })
                 }
-                BroadcastEvent::UpdatePlayer => {
-                    let state = state.lock().await;
+                BroadcastEvent::UpdatePlaylists => {
+                    let mut msg = playlists_json(&*state.lock().await);
+                    msg["msg"] = json!("playlists");
+                    msg
+                }
+                BroadcastEvent::Snackbar(text) => {
                     json!({
-                        "msg": "player",
-                        "playing": state.player.playing,
-                        "volume": state.player.volume,
+                        "msg": "snackbar",
+                        "text": text,
                     })
                 }
+                BroadcastEvent::Shutdown => {
+                    let frame = CloseFrame {
+                        code: CloseCode::Away,
+                        reason: "Server is shutting down".into(),
+                    };
+                    writer
+                        .lock()
+                        .await
+                        .send(Message::Close(Some(frame)))
+                        .await?;
+                    break;
+                }
             };
             let msg = serde_json::to_string(&msg)?;
             writer.lock().await.send(Message::Text(msg.into())).await?;
@@ -75,6 +501,7 @@ pub async fn handle(
     };
 
     let task2 = async {
+        let mut is_admin = false;
         loop {
             let msg = match reader.next().await {
                 Some(msg) => msg?,
@@ -108,17 +535,95 @@ pub async fn handle(
                 }
             };
 
+            let banned = match requester.user() {
+                Some(user) => state.lock().await.users.is_banned(user),
+                None => false,
+            };
+
             match msg.as_str() {
+                "yt" | "local" | "import" | "playlist_enqueue" if requester.user().is_none() => {
+                    // The frontend says hello on connect, bans rely on it
+                    send_snackbar("Reload the page to add songs").await?;
+                }
+                "yt" | "local" | "import" | "playlist_enqueue" if banned => {
+                    send_snackbar("You are not allowed to add songs").await?;
+                }
                 "yt" => {
                     send_snackbar("Request received! Please wait...").await?;
                     if let Some(String(link)) = obj.get("link") {
-                        info!("Received link (url: {})", link);
-                        let url = link.clone();
-                        let future = get_ytdlp(link.clone());
-                        state.lock().await.queue.push_task(future, url);
+                        enqueue_link(state, config, link.clone(), requester.clone()).await;
                         let _ = handler_event_tx.send(HandlerEvent::UpdateQueue).await;
                     }
                 }
+                "library" => {
+                    let query = match obj.get("query") {
+                        Some(String(query)) => query.as_str(),
+                        _ => "",
+                    };
+                    let msg = {
+                        let state = state.lock().await;
+                        let tracks = state
+                            .library
+                            .search(query)
+                            .take(LIBRARY_SEARCH_LIMIT)
+                            .map(|track| {
+                                json!({
+                                    "id": track.id,
+                                    "title": track.title,
+                                    "artist": track.artist,
+                                    "time": track.duration,
+                                })
+                            })
+                            .collect::<Vec<_>>();
+                        json!({
+                            "msg": "library",
+                            "query": query,
+                            "total": state.library.len(),
+                            "tracks": tracks,
+                        })
+                    };
+                    let msg = serde_json::to_string(&msg)?;
+                    writer.lock().await.send(Message::Text(msg.into())).await?;
+                }
+                "local" => match obj.get("id").and_then(|id| id.as_u64()) {
+                    Some(id) => {
+                        let track = state.lock().await.library.get(id as usize).cloned();
+                        if let Some(track) = track {
+                            info!("Received local track (path: {})", track.path.display());
+                            state
+                                .lock()
+                                .await
+                                .queue
+                                .push_local(track, requester.clone());
+                            let _ = handler_event_tx.send(HandlerEvent::UpdateQueue).await;
+                        } else {
+                            send_snackbar("Track not found in library").await?;
+                        }
+                    }
+                    None => {
+                        warn!("Malformed client message: msg = local, id not found");
+                    }
+                },
+                "retry" | "dismiss" => match obj.get("id").and_then(|id| id.as_u64()) {
+                    Some(id) => {
+                        let done = {
+                            let mut state = state.lock().await;
+                            if msg == "retry" {
+                                state.queue.retry(id)
+                            } else {
+                                state.queue.dismiss(id)
+                            }
+                        };
+                        if done {
+                            let _ = handler_event_tx.send(HandlerEvent::UpdateQueue).await;
+                        } else {
+                            send_snackbar("This song is not in a failed state").await?;
+                        }
+                    }
+                    None => {
+                        warn!("Malformed client message: msg = {msg}, id not found");
+                    }
+                },
                 "btn" => {
                     if let Some(String(action)) = obj.get("action") {
                         match action.as_str() {
@@ -142,8 +647,7 @@ pub async fn handle(
                 "volume" => match obj.get("volume") {
                     Some(Number(volume)) => {
                         if let Some(volume) = volume.as_f64() {
-                            state.lock().await.player.volume = volume as f32;
-                            let _ = handler_event_tx.send(HandlerEvent::SetVolume).await;
+                            set_volume(state, &handler_event_tx, volume as f32).await;
                         } else {
                             warn!(
                                 "Malformed client message: msg = volume, value is not float ({volume})"
@@ -157,6 +661,232 @@ pub async fn handle(
                         warn!("Malformed client message: msg = volume, volume not found");
                     }
                 },
+                "login" => match obj.get("token") {
+                    Some(String(token)) => {
+                        is_admin = config.admin.is_valid(token);
+                        let msg = serde_json::to_string(&json!({
+                            "msg": "login",
+                            "ok": is_admin,
+                        }))?;
+                        writer.lock().await.send(Message::Text(msg.into())).await?;
+                    }
+                    _ => {
+                        warn!("Malformed client message: msg = login, token not found");
+                    }
+                },
+                "hello" if requester.user().is_some() => {
+                    // One identity per connection
+                    warn!("Malformed client message: msg = hello, already said hello");
+                }
+                "hello" => match obj.get("token") {
+                    Some(String(token)) => {
+                        let user = state.lock().await.users.identify(token, addr).cloned();
+                        match user {
+                            Ok(user) => {
+                                requester.set_user(user.id);
+                                let msg = serde_json::to_string(&json!({
+                                    "msg": "hello",
+                                    "id": user.id,
+                                    "nickname": user.nickname,
+                                }))?;
+                                writer.lock().await.send(Message::Text(msg.into())).await?;
+                            }
+                            Err(IdentifyError::InvalidToken) => {
+                                warn!("Malformed client message: msg = hello, invalid token");
+                            }
+                            Err(error) => send_snackbar(error.user_message()).await?,
+                        }
+                    }
+                    _ => {
+                        warn!("Malformed client message: msg = hello, token not found");
+                    }
+                },
+                "nickname" => match (obj.get("nickname"), requester.user()) {
+                    (Some(String(nickname)), Some(user)) => {
+                        let nickname = state
+                            .lock()
+                            .await
+                            .users
+                            .set_nickname(user, nickname)
+                            .and_then(|user| user.nickname.clone());
+                        let msg = serde_json::to_string(&json!({
+                            "msg": "nickname",
+                            "nickname": nickname,
+                        }))?;
+                        writer.lock().await.send(Message::Text(msg.into())).await?;
+                        // Entries already in the queue show the new nickname
+                        let _ = handler_event_tx.send(HandlerEvent::UpdateQueue).await;
+                    }
+                    (Some(String(_)), None) => {
+                        warn!("Malformed client message: msg = nickname, hello not sent");
+                    }
+                    _ => {
+                        warn!("Malformed client message: msg = nickname, nickname not found");
+                    }
+                },
+                "react" => match (obj.get("reaction"), requester.user()) {
+                    (Some(String(reaction)), Some(_)) => {
+                        let reacted = {
+                            let mut state = state.lock().await;
+                            state
+                                .now_playing
+                                .as_mut()
+                                .map(|now_playing| now_playing.reactions.toggle(addr, reaction))
+                        };
+                        match reacted {
+                            Some(true) => {
+                                let _ = handler_event_tx.send(HandlerEvent::UpdateReactions).await;
+                            }
+                            Some(false) => {
+                                warn!("Unknown client message: msg = react, reaction = {reaction}");
+                            }
+                            None => send_snackbar("Nothing is playing").await?,
+                        }
+                    }
+                    (Some(String(_)), None) => {
+                        warn!("Malformed client message: msg = react, hello not sent");
+                    }
+                    _ => {
+                        warn!("Malformed client message: msg = react, reaction not found");
+                    }
+                },
+                "import" => match obj.get("content") {
+                    Some(String(content)) => {
+                        match import_playlist(state, config, content, &requester).await {
+                            Ok(added) => {
+                                send_snackbar(&format!("Added {added} songs")).await?;
+                                let _ = handler_event_tx.send(HandlerEvent::UpdateQueue).await;
+                            }
+                            Err(error) => send_snackbar(&error).await?,
+                        }
+                    }
+                    _ => {
+                        warn!("Malformed client message: msg = import, content not found");
+                    }
+                },
+                "export" => {
+                    let format = match obj.get("format") {
+                        Some(String(format)) => playlist::Format::parse(format),
+                        _ => Some(playlist::Format::M3u),
+                    };
+                    match format {
+                        Some(format) => {
+                            let content =
+                                export_playlist(&*state.lock().await, config, format, is_admin);
+                            let msg = serde_json::to_string(&json!({
+                                "msg": "export",
+                                "format": format.as_str(),
+                                "content": content,
+                            }))?;
+                            writer.lock().await.send(Message::Text(msg.into())).await?;
+                        }
+                        None => {
+                            warn!("Malformed client message: msg = export, unknown format");
+                        }
+                    }
+                }
+                "playlists" => {
+                    let mut msg = playlists_json(&*state.lock().await);
+                    msg["msg"] = json!("playlists");
+                    let msg = serde_json::to_string(&msg)?;
+                    writer.lock().await.send(Message::Text(msg.into())).await?;
+                }
+                "playlist_enqueue" => match obj.get("id").and_then(|id| id.as_u64()) {
+                    Some(id) => {
+                        match enqueue_playlist(state, config, &handler_event_tx, id, &requester)
+                            .await
+                        {
+                            Some(added) => send_snackbar(&format!("Added {added} songs")).await?,
+                            None => send_snackbar("Playlist not found").await?,
+                        }
+                    }
+                    None => {
+                        warn!("Malformed client message: msg = playlist_enqueue, id not found");
+                    }
+                },
+                "queue_resync" => {
+                    let msg = serde_json::to_string(&state.lock().await.queue_sync.snapshot())?;
+                    writer.lock().await.send(Message::Text(msg.into())).await?;
+                }
+                "audio_devices" | "audio_output" | "announcements" | "announce" | "users"
+                | "ban" | "unban" | "playlist_create" | "playlist_rename" | "playlist_delete"
+                | "playlist_add" | "playlist_remove" | "playlist_fallback" | "stats"
+                    if !is_admin =>
+                {
+                    send_snackbar("Only admins can do this").await?;
+                }
+                "audio_devices" => {
+                    let mut msg = audio_devices_json(state, config).await;
+                    msg["msg"] = json!("audio_devices");
+                    let msg = serde_json::to_string(&msg)?;
+                    writer.lock().await.send(Message::Text(msg.into())).await?;
+                }
+                "audio_output" => {
+                    let output = match obj.get("output") {
+                        None | Some(Null) => Ok(None),
+                        Some(output) => serde_json::from_value(output.clone()).map(Some),
+                    };
+                    match output {
+                        Ok(output) => set_audio_output(state, &handler_event_tx, output).await,
+                        Err(error) => {
+                            warn!("Malformed client message: msg = audio_output, {error}");
+                        }
+                    }
+                }
+                "announcements" => {
+                    let msg = serde_json::to_string(&json!({
+                        "msg": "announcements",
+                        "files": announcement::list(&config.announcements),
+                    }))?;
+                    writer.lock().await.send(Message::Text(msg.into())).await?;
+                }
+                "announce" => match obj.get("file") {
+                    Some(String(file)) => {
+                        if !announce(config, &handler_event_tx, file).await {
+                            send_snackbar("Announcement not found").await?;
+                        }
+                    }
+                    _ => {
+                        warn!("Malformed client message: msg = announce, file not found");
+                    }
+                },
+                "playlist_create" | "playlist_rename" | "playlist_delete" | "playlist_add"
+                | "playlist_remove" | "playlist_fallback" => {
+                    if let Err(error) = edit_playlist(state, &handler_event_tx, &obj).await {
+                        send_snackbar(error).await?;
+                    }
+                }
+                "stats" => {
+                    let param = |name| obj.get(name).and_then(Value::as_str);
+                    let msg = match StatsQuery::parse(param("from"), param("to"), None) {
+                        Ok(query) => match stats_json(config, query).await {
+                            Ok(mut msg) => {
+                                msg["msg"] = json!("stats");
+                                msg
+                            }
+                            Err(error) => json!({"msg": "stats", "error": error.to_string()}),
+                        },
+                        Err(error) => json!({"msg": "stats", "error": error}),
+                    };
+                    let msg = serde_json::to_string(&msg)?;
+                    writer.lock().await.send(Message::Text(msg.into())).await?;
+                }
+                "users" => {
+                    let mut msg = users_json(&*state.lock().await);
+                    msg["msg"] = json!("users");
+                    let msg = serde_json::to_string(&msg)?;
+                    writer.lock().await.send(Message::Text(msg.into())).await?;
+                }
+                "ban" | "unban" => match obj.get("user").and_then(|user| user.as_u64()) {
+                    Some(user) => {
+                        if !set_banned(state, &handler_event_tx, user, msg == "ban").await {
+                            send_snackbar("User not found").await?;
+                        }
+                    }
+                    None => {
+                        warn!("Malformed client message: msg = {msg}, user not found");
+                    }
+                },
                 _ => {
                     warn!("Unknown client message: msg = {msg}");
                 }
@@ -0,0 +1,186 @@
+use std::{
+    sync::{
+        LazyLock,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use log::{error, info, warn};
+use sd_notify::NotifyState;
+use serde_json::{Value, json};
+use smol::{Timer, lock::Mutex};
+
+use crate::yt_dlp::ytdlp_version;
+
+/// Liveness of the long running loops, used by `/healthz` and the systemd watchdog
+pub static HEALTH: Health = Health {
+    vlc_ready: AtomicBool::new(false),
+    player: Heartbeat::new(),
+    queue: Heartbeat::new(),
+};
+
+/// A loop is considered stuck when it hasn't ticked for this long
+const STALE_AFTER: Duration = Duration::from_secs(10);
+/// How long a `yt-dlp --version` check is reused, probes would otherwise spawn one each time
+const YTDLP_CHECK_TTL: Duration = Duration::from_secs(60);
+
+/// Last `yt-dlp --version` check, the lock also keeps concurrent probes from running it twice
+static YTDLP_CHECK: Mutex<Option<(Instant, Value)>> = Mutex::new(None);
+
+static START: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+pub struct Health {
+    pub vlc_ready: AtomicBool,
+    /// Ticked by the `player` loop
+    pub player: Heartbeat,
+    /// Ticked by the `process_queue` loop
+    pub queue: Heartbeat,
+}
+
+pub struct Heartbeat {
+    /// Milliseconds since [`START`] of the last tick, `u64::MAX` if it never ticked
+    last: AtomicU64,
+}
+
+impl Heartbeat {
+    const fn new() -> Self {
+        Heartbeat {
+            last: AtomicU64::new(u64::MAX),
+        }
+    }
+
+    pub fn tick(&self) {
+        let now = START.elapsed().as_millis() as u64;
+        self.last.store(now, Ordering::Relaxed);
+    }
+
+    /// Time since the last tick, `None` if it never ticked
+    fn age(&self) -> Option<Duration> {
+        match self.last.load(Ordering::Relaxed) {
+            u64::MAX => None,
+            last => Some(START.elapsed().saturating_sub(Duration::from_millis(last))),
+        }
+    }
+
+    fn is_alive(&self) -> bool {
+        self.age().is_some_and(|age| age < STALE_AFTER)
+    }
+
+    fn json(&self) -> Value {
+        json!({
+            "alive": self.is_alive(),
+            "last_tick_ms": self.age().map(|age| age.as_millis() as u64),
+        })
+    }
+}
+
+impl Health {
+    fn vlc_ready(&self) -> bool {
+        self.vlc_ready.load(Ordering::Relaxed)
+    }
+
+    fn is_alive(&self) -> bool {
+        self.vlc_ready() && self.player.is_alive() && self.queue.is_alive()
+    }
+
+    /// No loop is stuck, the player is not expected to tick while VLC initialisation is retried
+    fn is_responsive(&self) -> bool {
+        self.queue.is_alive() && (!self.vlc_ready() || self.player.is_alive())
+    }
+}
+
+/// Result of `yt-dlp --version`, cached for [`YTDLP_CHECK_TTL`]
+async fn ytdlp_check() -> Value {
+    let mut check = YTDLP_CHECK.lock().await;
+    if let Some((checked_at, result)) = &*check
+        && checked_at.elapsed() < YTDLP_CHECK_TTL
+    {
+        return result.clone();
+    }
+    let result = match ytdlp_version().await {
+        Ok(version) => json!({"ok": true, "version": version}),
+        Err(error) => json!({"ok": false, "error": error.to_string()}),
+    };
+    *check = Some((Instant::now(), result.clone()));
+    result
+}
+
+/// Body of the `/healthz` response, and whether everything is healthy
+pub async fn report() -> (bool, Value) {
+    let ytdlp = ytdlp_check().await;
+    let healthy = HEALTH.is_alive() && ytdlp["ok"] == true;
+    let report = json!({
+        "ok": healthy,
+        "vlc": HEALTH.vlc_ready.load(Ordering::Relaxed),
+        "ytdlp": ytdlp,
+        "player": HEALTH.player.json(),
+        "queue_processor": HEALTH.queue.json(),
+    });
+    (healthy, report)
+}
+
+/// Status line shown by `systemctl status`
+fn status() -> &'static str {
+    if HEALTH.vlc_ready() {
+        "Playing"
+    } else {
+        "Waiting for VLC to initialise"
+    }
+}
+
+/// Tell systemd we are ready, then keep feeding its watchdog and reporting the VLC state
+///
+/// Called once the server is listening. Readiness does not wait for VLC, whose initialisation
+/// is retried for as long as it takes, the status line says whether it is up.
+/// The watchdog is only fed while the loops are alive, so systemd restarts us when one gets stuck
+pub async fn notify_systemd() {
+    let mut vlc_ready = HEALTH.vlc_ready();
+    if let Err(error) =
+        sd_notify::notify(false, &[NotifyState::Ready, NotifyState::Status(status())])
+    {
+        error!("Failed to notify systemd: {error}");
+        return;
+    }
+
+    let mut usec = 0;
+    let period = if sd_notify::watchdog_enabled(false, &mut usec) {
+        let period = Duration::from_micros(usec) / 2;
+        info!(
+            "systemd watchdog enabled, notifying every {}ms",
+            period.as_millis()
+        );
+        Some(period)
+    } else {
+        None
+    };
+    let mut stuck = false;
+    loop {
+        Timer::after(period.unwrap_or(Duration::from_secs(1))).await;
+        if vlc_ready != HEALTH.vlc_ready() {
+            vlc_ready = HEALTH.vlc_ready();
+            if let Err(error) = sd_notify::notify(false, &[NotifyState::Status(status())]) {
+                error!("Failed to notify systemd: {error}");
+            }
+        }
+        if period.is_none() {
+            // Only the status needs updating, and it can't change once VLC is up
+            if vlc_ready {
+                return;
+            }
+            continue;
+        }
+        if HEALTH.is_responsive() {
+            if stuck {
+                info!("All loops are alive again");
+                stuck = false;
+            }
+            if let Err(error) = sd_notify::notify(false, &[NotifyState::Watchdog]) {
+                error!("Failed to notify systemd watchdog: {error}");
+            }
+        } else if !stuck {
+            warn!("A loop is stuck, stop feeding the systemd watchdog");
+            stuck = true;
+        }
+    }
+}
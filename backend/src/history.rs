@@ -0,0 +1,166 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, ErrorKind, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::{song_queue::Song, users::User};
+
+const HISTORY_FILE: &str = "history.jsonl";
+/// Number of records kept in memory
+const RECENT_LIMIT: usize = 500;
+
+/// Songs that were played, appended to `history.jsonl` in the data directory
+#[derive(Debug, Default)]
+pub struct History {
+    recent: VecDeque<HistoryRecord>,
+    path: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HistoryRecord {
+    /// Unix timestamp (seconds) when the song started playing
+    pub started_at: u64,
+    pub title: String,
+    /// Page URL, or file path for local files
+    pub url: String,
+    pub source: String,
+    /// Channel, or artist for local files
+    pub channel: Option<String>,
+    pub duration: Option<u32>,
+    /// Seconds actually played
+    pub played: u32,
+    pub skipped: bool,
+    /// Cut off by a shutdown, the song is played again after the restart
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub interrupted: bool,
+    /// Id of the user who requested the song
+    pub requester_id: Option<u64>,
+    /// Nickname of the requester when the song played
+    pub requester: Option<String>,
+    /// Number of listeners who sent each reaction
+    #[serde(default)]
+    pub reactions: BTreeMap<String, u32>,
+}
+
+impl History {
+    /// Load the most recent records from `data_dir`
+    pub fn load(data_dir: &Path) -> History {
+        let path = file(data_dir);
+        let mut recent = VecDeque::new();
+        match read_records(&path) {
+            Ok(records) => {
+                for record in records {
+                    if recent.len() == RECENT_LIMIT {
+                        recent.pop_front();
+                    }
+                    recent.push_back(record);
+                }
+            }
+            Err(error) if error.kind() == ErrorKind::NotFound => {}
+            Err(error) => {
+                error!("Failed to read history {}: {error}", path.display());
+            }
+        }
+        History {
+            recent,
+            path: Some(path),
+        }
+    }
+
+    /// Add a record, the returned future completes once it is appended to the file
+    ///
+    /// The file is written on a blocking thread, await it after releasing the state lock.
+    pub fn push(&mut self, record: HistoryRecord) -> impl Future<Output = ()> + use<> {
+        let write = self.path.clone().map(|path| {
+            let record = record.clone();
+            smol::unblock(move || {
+                if let Err(error) = append_record(&path, &record) {
+                    error!("Failed to write history {}: {error}", path.display());
+                }
+            })
+        });
+        if self.recent.len() == RECENT_LIMIT {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(record);
+        async {
+            if let Some(write) = write {
+                write.await;
+            }
+        }
+    }
+
+    /// Most recent records, newest first
+    pub fn recent(&self) -> impl Iterator<Item = &HistoryRecord> {
+        self.recent.iter().rev()
+    }
+}
+
+impl HistoryRecord {
+    pub fn new(
+        song: &Song,
+        requester: Option<&User>,
+        started_at: u64,
+        played: u32,
+        skipped: bool,
+    ) -> Self {
+        HistoryRecord {
+            started_at,
+            title: song.title().to_owned(),
+            url: song.url(),
+            source: song.source().to_owned(),
+            channel: song.channel().map(str::to_owned),
+            duration: song.duration(),
+            played,
+            skipped,
+            interrupted: false,
+            requester_id: requester.map(|user| user.id),
+            requester: requester.and_then(|user| user.nickname.clone()),
+            reactions: BTreeMap::new(),
+        }
+    }
+}
+
+/// Path of the history file in `data_dir`
+pub fn file(data_dir: &Path) -> PathBuf {
+    data_dir.join(HISTORY_FILE)
+}
+
+pub fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default()
+}
+
+/// Read every record in the history file, skipping malformed lines
+pub fn read_records(path: &Path) -> std::io::Result<Vec<HistoryRecord>> {
+    let file = File::open(path)?;
+    let mut records = Vec::new();
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        match serde_json::from_str(&line?) {
+            Ok(record) => records.push(record),
+            Err(error) => {
+                warn!("Malformed history record at line {}: {error}", index + 1);
+            }
+        }
+    }
+    Ok(records)
+}
+
+fn append_record(path: &Path, record: &HistoryRecord) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    let mut line = serde_json::to_string(record)?;
+    line.push('\n');
+    file.write_all(line.as_bytes())?;
+    Ok(())
+}
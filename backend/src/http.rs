@@ -0,0 +1,571 @@
+use std::{net::IpAddr, time::Duration};
+
+use async_tungstenite::{
+    WebSocketStream,
+    tungstenite::{handshake::derive_accept_key, protocol::Role},
+};
+use log::{info, warn};
+use serde_json::{Value, json};
+use smol::{
+    Timer,
+    channel::{self, Sender},
+    future::FutureExt,
+    io::{AsyncReadExt, AsyncWriteExt},
+    lock::Mutex,
+    net::TcpStream,
+};
+
+use crate::{
+    AppState, BroadcastEvent, HandlerEvent, announcement, assets,
+    audio_output::AudioOutput,
+    config::Config,
+    handler::{
+        announce, audio_devices_json, edit_playlist, enqueue_link, enqueue_playlist,
+        export_playlist, handle, import_playlist, now_playing_json, player_json, playlists_json,
+        queue_json_with_etas, set_audio_output, set_banned, set_volume, users_json,
+    },
+    health,
+    metrics::{self, ClientGuard},
+    playlist,
+    song_queue::Requester,
+    stats::{self, Format, StatsQuery},
+};
+
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+/// A client that doesn't send its whole request within this time is dropped
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+const HISTORY_DEFAULT_LIMIT: usize = 50;
+
+struct Request {
+    method: String,
+    path: String,
+    query: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+/// Serve one connection, either as WebSocket (upgrade on `/ws`) or as plain HTTP
+pub async fn serve(
+    mut stream: TcpStream,
+    state: &Mutex<AppState<'_>>,
+    config: &Config,
+    event_listeners: &Mutex<Vec<Sender<BroadcastEvent>>>,
+    handler_event_tx: Sender<HandlerEvent>,
+) -> anyhow::Result<()> {
+    let read = async { Some(read_request(&mut stream).await) }.or(async {
+        Timer::after(READ_TIMEOUT).await;
+        None
+    });
+    let request = match read.await {
+        Some(Ok(Some(request))) => request,
+        Some(Ok(None)) => return Ok(()),
+        Some(Err(error)) => {
+            let response = Response::error(400, &error.to_string());
+            return write_response(&mut stream, response).await;
+        }
+        None => {
+            let response = Response::error(408, "Request timed out");
+            return write_response(&mut stream, response).await;
+        }
+    };
+
+    if request.is_websocket_upgrade() {
+        // `/` is kept for reverse proxies that strip the `/ws/` prefix
+        if !matches!(request.path.as_str(), "/" | "/ws" | "/ws/") {
+            return write_response(&mut stream, Response::error(404, "Not found")).await;
+        }
+        let Some(key) = request.header("sec-websocket-key") else {
+            let response = Response::error(400, "Missing Sec-WebSocket-Key");
+            return write_response(&mut stream, response).await;
+        };
+        let addr = request.client_addr(stream.peer_addr()?.ip());
+        let accept = derive_accept_key(key.as_bytes());
+        let head = format!(
+            "HTTP/1.1 101 Switching Protocols\r\n\
+             Upgrade: websocket\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Accept: {accept}\r\n\r\n"
+        );
+        stream.write_all(head.as_bytes()).await?;
+        let websocket = WebSocketStream::from_raw_socket(stream, Role::Server, None).await;
+
+        let (tx, rx) = channel::unbounded();
+        let _ = tx.send(BroadcastEvent::UpdatePlayer).await;
+        let _ = tx.send(BroadcastEvent::SendQueue).await;
+        event_listeners.lock().await.push(tx.clone());
+        let _client = ClientGuard::new();
+        return handle(websocket, addr, state, config, (tx, rx), handler_event_tx).await;
+    }
+
+    let response = route(&request, state, config, &handler_event_tx).await;
+    write_response(&mut stream, response).await
+}
+
+async fn route(
+    request: &Request,
+    state: &Mutex<AppState<'_>>,
+    config: &Config,
+    handler_event_tx: &Sender<HandlerEvent>,
+) -> Response {
+    match (request.method.as_str(), request.path.as_str()) {
+        ("GET", "/api/now-playing") => {
+            let now_playing = now_playing_json(&*state.lock().await);
+            Response::json(200, json!({ "now_playing": now_playing }))
+        }
+        ("GET", "/api/queue") => {
+            Response::json(200, queue_json_with_etas(&*state.lock().await, config))
+        }
+        ("GET", "/api/player") => Response::json(200, player_json(&*state.lock().await, config)),
+        ("GET", "/api/history") => {
+            let limit = request
+                .query_param("limit")
+                .and_then(|limit| limit.parse().ok())
+                .unwrap_or(HISTORY_DEFAULT_LIMIT);
+            let state = state.lock().await;
+            let history = state.history.recent().take(limit).collect::<Vec<_>>();
+            Response::json(200, json!({ "history": history }))
+        }
+        ("GET", "/api/export") => {
+            let format = request.query_param("format").unwrap_or("m3u");
+            // Full paths of local files with the admin token, relative ones otherwise
+            let admin = authorize(request, config).is_ok();
+            match playlist::Format::parse(format) {
+                Some(format) => Response {
+                    status: 200,
+                    content_type: format.content_type(),
+                    body: export_playlist(&*state.lock().await, config, format, admin).into_bytes(),
+                },
+                None => Response::error(400, "format must be m3u or json"),
+            }
+        }
+        ("POST", "/api/import") => {
+            if let Err(response) = authorize(request, config) {
+                return response;
+            }
+            let content = String::from_utf8_lossy(&request.body);
+            match import_playlist(state, config, &content, &Requester::default()).await {
+                Ok(added) => {
+                    let _ = handler_event_tx.send(HandlerEvent::UpdateQueue).await;
+                    Response::json(200, json!({ "added": added }))
+                }
+                Err(error) => Response::error(400, &error),
+            }
+        }
+        ("GET", "/api/playlists") => Response::json(200, playlists_json(&*state.lock().await)),
+        ("POST", "/api/playlists") => {
+            if let Err(response) = authorize(request, config) {
+                return response;
+            }
+            match request.json() {
+                Some(Value::Object(command)) => {
+                    match edit_playlist(state, handler_event_tx, &command).await {
+                        Ok(()) => Response::ok(),
+                        Err(error) => Response::error(400, error),
+                    }
+                }
+                _ => Response::error(400, "Expected a playlist_* command"),
+            }
+        }
+        ("POST", "/api/playlist-enqueue") => {
+            if let Err(response) = authorize(request, config) {
+                return response;
+            }
+            let id = request
+                .json()
+                .and_then(|body| body.get("id").and_then(Value::as_u64));
+            let Some(id) = id else {
+                return Response::error(400, "Expected {\"id\": <playlist id>}");
+            };
+            let requester = Requester::default();
+            match enqueue_playlist(state, config, handler_event_tx, id, &requester).await {
+                Some(added) => Response::json(200, json!({ "added": added })),
+                None => Response::error(404, "Playlist not found"),
+            }
+        }
+        ("GET", "/api/stats") => {
+            if let Err(response) = authorize(request, config) {
+                return response;
+            }
+            let query = StatsQuery::parse(
+                request.query_param("from"),
+                request.query_param("to"),
+                request.query_param("limit"),
+            );
+            let format = Format::parse(request.query_param("format").unwrap_or("json"));
+            let (query, format) = match (query, format) {
+                (Ok(query), Some(format)) => (query, format),
+                (Err(error), _) => return Response::error(400, &error),
+                (_, None) => return Response::error(400, "format must be json or csv"),
+            };
+            let data_dir = config.data_dir.clone();
+            match smol::unblock(move || stats::compute(&data_dir, query)).await {
+                Ok(stats) if format == Format::Csv => Response {
+                    status: 200,
+                    content_type: "text/csv; charset=utf-8",
+                    body: stats.to_csv().into_bytes(),
+                },
+                Ok(stats) => Response::json(200, json!(stats)),
+                Err(error) => Response::error(500, &error.to_string()),
+            }
+        }
+        ("GET", "/api/audio-devices") => match authorize(request, config) {
+            Ok(()) => Response::json(200, audio_devices_json(state, config).await),
+            Err(response) => response,
+        },
+        ("POST", "/api/audio-output") => {
+            if let Err(response) = authorize(request, config) {
+                return response;
+            }
+            let output = match request.json() {
+                None | Some(Value::Null) => Ok(None),
+                Some(body) => serde_json::from_value::<AudioOutput>(body).map(Some),
+            };
+            match output {
+                Ok(output) => {
+                    set_audio_output(state, handler_event_tx, output).await;
+                    Response::ok()
+                }
+                Err(error) => Response::error(400, &error.to_string()),
+            }
+        }
+        ("GET", "/api/announcements") => match authorize(request, config) {
+            Ok(()) => Response::json(
+                200,
+                json!({ "files": announcement::list(&config.announcements) }),
+            ),
+            Err(response) => response,
+        },
+        ("POST", "/api/announce") => {
+            if let Err(response) = authorize(request, config) {
+                return response;
+            }
+            let file = request
+                .json()
+                .and_then(|body| body.get("file").and_then(Value::as_str).map(str::to_owned));
+            match file {
+                Some(file) => {
+                    if announce(config, handler_event_tx, &file).await {
+                        Response::ok()
+                    } else {
+                        Response::error(404, "Announcement not found")
+                    }
+                }
+                None => Response::error(400, "Expected {\"file\": <string>}"),
+            }
+        }
+        ("GET", "/api/users") => match authorize(request, config) {
+            Ok(()) => Response::json(200, users_json(&*state.lock().await)),
+            Err(response) => response,
+        },
+        ("POST", path @ ("/api/ban" | "/api/unban")) => {
+            if let Err(response) = authorize(request, config) {
+                return response;
+            }
+            let user = request
+                .json()
+                .and_then(|body| body.get("user").and_then(Value::as_u64));
+            match user {
+                Some(user) => {
+                    if set_banned(state, handler_event_tx, user, path == "/api/ban").await {
+                        Response::ok()
+                    } else {
+                        Response::error(404, "User not found")
+                    }
+                }
+                None => Response::error(400, "Expected {\"user\": <id>}"),
+            }
+        }
+        ("GET", "/healthz") => {
+            let (healthy, report) = health::report().await;
+            Response::json(if healthy { 200 } else { 503 }, report)
+        }
+        ("GET", "/metrics") => Response {
+            status: 200,
+            content_type: "text/plain; version=0.0.4",
+            body: metrics::render(&*state.lock().await).into_bytes(),
+        },
+        ("POST", "/api/queue") => match authorize(request, config) {
+            Ok(()) => enqueue(request, state, config, handler_event_tx).await,
+            Err(response) => response,
+        },
+        ("POST", path @ ("/api/skip" | "/api/pause" | "/api/resume")) => {
+            if let Err(response) = authorize(request, config) {
+                return response;
+            }
+            let event = match path {
+                "/api/skip" => HandlerEvent::Skip,
+                "/api/pause" => HandlerEvent::Pause,
+                _ => HandlerEvent::Resume,
+            };
+            let _ = handler_event_tx.send(event).await;
+            Response::ok()
+        }
+        ("POST", "/api/volume") => {
+            if let Err(response) = authorize(request, config) {
+                return response;
+            }
+            let volume = request
+                .json()
+                .and_then(|body| body.get("volume").and_then(Value::as_f64));
+            match volume {
+                Some(volume) => {
+                    set_volume(state, handler_event_tx, volume as f32).await;
+                    Response::ok()
+                }
+                None => Response::error(400, "Expected {\"volume\": <number>}"),
+            }
+        }
+        (
+            _,
+            "/api/now-playing"
+            | "/api/queue"
+            | "/api/player"
+            | "/api/history"
+            | "/api/stats"
+            | "/api/skip"
+            | "/api/pause"
+            | "/api/resume"
+            | "/api/volume"
+            | "/api/audio-devices"
+            | "/api/audio-output"
+            | "/api/announcements"
+            | "/api/announce"
+            | "/api/users"
+            | "/api/ban"
+            | "/api/unban"
+            | "/api/import"
+            | "/api/export"
+            | "/api/playlists"
+            | "/api/playlist-enqueue"
+            | "/metrics"
+            | "/healthz",
+        ) => Response::error(405, "Method not allowed"),
+        ("GET", path) if !path.starts_with("/api/") => {
+            match assets::get(config.http.static_dir.as_deref(), path).await {
+                Some(asset) => Response {
+                    status: 200,
+                    content_type: asset.content_type,
+                    body: asset.body,
+                },
+                None => Response::error(404, "Not found"),
+            }
+        }
+        _ => Response::error(404, "Not found"),
+    }
+}
+
+/// Admin requests carry `Authorization: Bearer <admin.token>`
+fn authorize(request: &Request, config: &Config) -> Result<(), Response> {
+    if config.admin.token.is_none() {
+        return Err(Response::error(403, "Admin commands are disabled"));
+    }
+    let token = request
+        .header("authorization")
+        .and_then(|value| value.strip_prefix("Bearer "));
+    match token {
+        Some(token) if config.admin.is_valid(token.trim()) => Ok(()),
+        _ => Err(Response::error(401, "Invalid admin token")),
+    }
+}
+
+/// Body is either `{"link": "<url>"}`, `{"local": <library id>}` or a plain URL
+async fn enqueue(
+    request: &Request,
+    state: &Mutex<AppState<'_>>,
+    config: &Config,
+    handler_event_tx: &Sender<HandlerEvent>,
+) -> Response {
+    let body = request.json();
+    if let Some(id) = body.as_ref().and_then(|body| body.get("local")?.as_u64()) {
+        let track = state.lock().await.library.get(id as usize).cloned();
+        let Some(track) = track else {
+            return Response::error(404, "Track not found in library");
+        };
+        info!("Received local track (path: {})", track.path.display());
+        state
+            .lock()
+            .await
+            .queue
+            .push_local(track, Requester::default());
+    } else {
+        let link = match &body {
+            Some(body) => body.get("link").and_then(Value::as_str).map(str::to_owned),
+            None => std::str::from_utf8(&request.body)
+                .ok()
+                .map(|body| body.trim().to_owned())
+                .filter(|link| !link.is_empty()),
+        };
+        let Some(link) = link else {
+            return Response::error(400, "Expected a link");
+        };
+        enqueue_link(state, config, link, Requester::default()).await;
+    }
+    let _ = handler_event_tx.send(HandlerEvent::UpdateQueue).await;
+    Response::json(202, json!({ "ok": true }))
+}
+
+/// Read the request head and body, `None` if the connection is closed before that
+async fn read_request(stream: &mut TcpStream) -> anyhow::Result<Option<Request>> {
+    let mut buf = Vec::new();
+    let mut chunk = [0; 4096];
+    let (mut request, head_len, content_length) = loop {
+        let n = stream.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        buf.extend_from_slice(&chunk[..n]);
+
+        let mut headers = [httparse::EMPTY_HEADER; 64];
+        let mut parsed = httparse::Request::new(&mut headers);
+        match parsed.parse(&buf)? {
+            httparse::Status::Complete(head_len) => {
+                let target = parsed.path.unwrap_or("/");
+                let (path, query) = target.split_once('?').unwrap_or((target, ""));
+                let headers = parsed
+                    .headers
+                    .iter()
+                    .map(|header| {
+                        let value = String::from_utf8_lossy(header.value).into_owned();
+                        (header.name.to_ascii_lowercase(), value)
+                    })
+                    .collect::<Vec<_>>();
+                let request = Request {
+                    method: parsed.method.unwrap_or_default().to_owned(),
+                    path: path.to_owned(),
+                    query: query.to_owned(),
+                    headers,
+                    body: Vec::new(),
+                };
+                let content_length = request
+                    .header("content-length")
+                    .map(|length| length.trim().parse::<usize>())
+                    .transpose()?
+                    .unwrap_or(0);
+                break (request, head_len, content_length);
+            }
+            httparse::Status::Partial => {
+                if buf.len() > MAX_HEAD_SIZE {
+                    return Err(anyhow::anyhow!("Request head too large"));
+                }
+            }
+        }
+    };
+
+    if content_length > MAX_BODY_SIZE {
+        return Err(anyhow::anyhow!("Request body too large"));
+    }
+    let mut body = buf.split_off(head_len);
+    if body.len() < content_length {
+        let start = body.len();
+        body.resize(content_length, 0);
+        stream.read_exact(&mut body[start..]).await?;
+    }
+    body.truncate(content_length);
+    request.body = body;
+    Ok(Some(request))
+}
+
+async fn write_response(stream: &mut TcpStream, response: Response) -> anyhow::Result<()> {
+    let head = format!(
+        "HTTP/1.1 {} {}\r\n\
+         Content-Type: {}\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        response.status,
+        reason_phrase(response.status),
+        response.content_type,
+        response.body.len(),
+    );
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(&response.body).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        202 => "Accepted",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Address of the client, a reverse proxy on the same host passes it in `X-Forwarded-For`
+    fn client_addr(&self, peer: IpAddr) -> IpAddr {
+        if !peer.is_loopback() {
+            return peer;
+        }
+        self.header("x-forwarded-for")
+            .and_then(|addrs| addrs.rsplit(',').next())
+            .and_then(|addr| addr.trim().parse().ok())
+            .unwrap_or(peer)
+    }
+
+    fn is_websocket_upgrade(&self) -> bool {
+        self.method == "GET"
+            && self
+                .header("upgrade")
+                .is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket"))
+    }
+
+    /// Value of a query parameter, no percent-decoding is done
+    fn query_param(&self, name: &str) -> Option<&str> {
+        self.query
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value)
+    }
+
+    fn json(&self) -> Option<Value> {
+        match serde_json::from_slice(&self.body) {
+            Ok(value) => Some(value),
+            Err(error) => {
+                if self.header("content-type") == Some("application/json") {
+                    warn!("Malformed JSON body on {}: {error}", self.path);
+                }
+                None
+            }
+        }
+    }
+}
+
+impl Response {
+    fn json(status: u16, value: Value) -> Self {
+        Response {
+            status,
+            content_type: "application/json",
+            body: value.to_string().into_bytes(),
+        }
+    }
+
+    fn ok() -> Self {
+        Response::json(200, json!({ "ok": true }))
+    }
+
+    fn error(status: u16, message: &str) -> Self {
+        Response::json(status, json!({ "error": message }))
+    }
+}
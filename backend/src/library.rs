@@ -0,0 +1,184 @@
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+};
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use smol::lock::Mutex;
+
+use crate::AppState;
+
+const AUDIO_EXTENSIONS: &[&str] = &[
+    "mp3", "flac", "ogg", "oga", "opus", "m4a", "aac", "wav", "wma", "aiff", "aif",
+];
+
+#[derive(Debug, Default)]
+pub struct Library {
+    tracks: Vec<LocalTrack>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LocalTrack {
+    /// Index in the current scan, not saved: saved tracks are found again by path
+    #[serde(skip)]
+    pub id: usize,
+    pub path: PathBuf,
+    pub title: String,
+    pub artist: Option<String>,
+    pub duration: Option<u32>,
+}
+
+/// Scan the configured directories and store the result in `state.library`
+pub async fn load_library(state: &Mutex<AppState<'_>>, dirs: Vec<PathBuf>) {
+    if dirs.is_empty() {
+        return;
+    }
+    // vlc::Instance is not Send, so the whole scan happens on a blocking thread
+    let tracks = smol::unblock(move || scan(&dirs)).await;
+    info!("Local library loaded ({} tracks)", tracks.len());
+    let mut state = state.lock().await;
+    let state = &mut *state;
+    state.library = Library { tracks };
+    // Tracks restored from `queue.json` only have what was saved
+    state.queue.refresh_local(&state.library);
+}
+
+fn scan(dirs: &[PathBuf]) -> Vec<LocalTrack> {
+    let Some(vlc_instance) = vlc::Instance::new() else {
+        error!("Failed to create VLC instance for scanning local library");
+        return Vec::new();
+    };
+
+    let mut paths = Vec::new();
+    let mut visited = HashSet::new();
+    for dir in dirs {
+        collect_audio_files(dir, &mut visited, &mut paths);
+    }
+    paths.sort();
+
+    paths
+        .into_iter()
+        .enumerate()
+        .map(|(id, path)| read_tags(&vlc_instance, id, path))
+        .collect()
+}
+
+/// Symlinks are followed, `visited` holds the canonical paths of directories already scanned
+/// so a symlink loop or a directory linked twice is only scanned once
+fn collect_audio_files(dir: &Path, visited: &mut HashSet<PathBuf>, paths: &mut Vec<PathBuf>) {
+    match dir.canonicalize() {
+        Ok(canonical) => {
+            if !visited.insert(canonical) {
+                return;
+            }
+        }
+        Err(error) => {
+            warn!(
+                "Failed to read library directory {}: {error}",
+                dir.display()
+            );
+            return;
+        }
+    }
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(error) => {
+            warn!(
+                "Failed to read library directory {}: {error}",
+                dir.display()
+            );
+            return;
+        }
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            collect_audio_files(&path, visited, paths);
+        } else if is_audio_file(&path) {
+            paths.push(path);
+        }
+    }
+}
+
+fn is_audio_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| AUDIO_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+}
+
+fn read_tags(vlc_instance: &vlc::Instance, id: usize, path: PathBuf) -> LocalTrack {
+    let file_name = path
+        .file_stem()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    let Some(media) = vlc::Media::new_path(vlc_instance, &path) else {
+        warn!("Failed to read tags of {}", path.display());
+        return LocalTrack {
+            id,
+            path,
+            title: file_name,
+            artist: None,
+            duration: None,
+        };
+    };
+    media.parse();
+
+    let title = media
+        .get_meta(vlc::Meta::Title)
+        .filter(|title| !title.is_empty())
+        .unwrap_or(file_name);
+    let artist = media
+        .get_meta(vlc::Meta::Artist)
+        .filter(|artist| !artist.is_empty());
+    let duration = media
+        .duration()
+        .and_then(|ms| u32::try_from(ms / 1000).ok());
+
+    LocalTrack {
+        id,
+        path,
+        title,
+        artist,
+        duration,
+    }
+}
+
+impl Library {
+    pub fn get(&self, id: usize) -> Option<&LocalTrack> {
+        self.tracks.get(id)
+    }
+
+    /// Case insensitive search on title, artist and file name
+    pub fn search<'a>(&'a self, query: &str) -> impl Iterator<Item = &'a LocalTrack> {
+        let query = query.to_lowercase();
+        self.tracks.iter().filter(move |track| {
+            query.is_empty()
+                || track.title.to_lowercase().contains(&query)
+                || track
+                    .artist
+                    .as_ref()
+                    .is_some_and(|artist| artist.to_lowercase().contains(&query))
+                || track
+                    .path
+                    .file_name()
+                    .is_some_and(|name| name.to_string_lossy().to_lowercase().contains(&query))
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.tracks.len()
+    }
+
+    /// Track at `path`, or ending with it for a relative path, as exported for non-admins
+    pub fn find_path(&self, path: &Path) -> Option<&LocalTrack> {
+        if path.as_os_str().is_empty() {
+            return None;
+        }
+        if path.is_relative() {
+            return self.tracks.iter().find(|track| track.path.ends_with(path));
+        }
+        self.tracks.iter().find(|track| track.path == path)
+    }
+}
@@ -1,115 +1,313 @@
+mod announcement;
+mod assets;
+mod audio_output;
+mod config;
 mod handler;
+mod health;
+mod history;
+mod http;
+mod library;
+mod metrics;
 mod player;
+mod playlist;
+mod playlists;
+mod queue_sync;
+mod reactions;
+mod scheduler;
+mod settings;
 mod song_queue;
+mod stats;
+mod store;
+mod tts;
+mod users;
 mod yt_dlp;
 
-use std::convert::Infallible;
+use std::{
+    fmt, io, mem::take, path::PathBuf, pin::pin, process::ExitCode, sync::Arc, time::Duration,
+};
 
+use async_signal::{Signal, Signals};
 use simplelog::{ColorChoice, ConfigBuilder, TermLogger, TerminalMode};
 use smol::prelude::*;
 use smol::{
-    Executor, block_on,
-    channel::{self, RecvError},
-    future::zip,
+    Executor, Timer, block_on,
+    channel::{self, Sender},
     lock::Mutex,
     net::TcpListener,
 };
 
-use log::{LevelFilter, error};
+use log::{LevelFilter, error, info};
 use systemd_journal_logger::{JournalLog, connected_to_journal};
 
-use handler::handle;
+use audio_output::AudioOutput;
+use config::Config;
+use history::History;
+use http::serve;
+use library::{Library, load_library};
 use player::player;
-use song_queue::{SongQueue, process_queue};
-use yt_dlp::YoutubeInfo;
+use playlists::Playlists;
+use queue_sync::{QueueSync, broadcast_queue};
+use reactions::Reactions;
+use scheduler::{ScheduleRule, scheduler};
+use settings::Settings;
+use song_queue::{Song, SongQueue, process_queue, save_queue, take_saved_queue};
+use users::Users;
+
+/// Longest wait for the player to fade out, and for clients to close their connection
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(3);
 
 #[derive(Debug, Default)]
 struct AppState<'ex> {
-    now_playing: Option<YoutubeInfo>,
+    now_playing: Option<NowPlaying>,
     queue: SongQueue<'ex>,
     player: PlayerState,
+    library: Library,
+    history: History,
+    settings: Settings,
+    users: Users,
+    playlists: Playlists,
+    queue_sync: QueueSync,
+}
+
+#[derive(Debug)]
+struct NowPlaying {
+    song: Song,
+    /// User who requested the song, if known
+    requester: Option<u64>,
+    reactions: Reactions,
+    /// Seconds played so far, updated by the player
+    position: u32,
+    /// Unix timestamp (seconds) the song started at, moved forward by pauses; `None` while paused
+    started_at: Option<u64>,
 }
 
 #[derive(Debug)]
 struct PlayerState {
     playing: bool,
-    volume: f32,
+    /// Volume set by users, the schedule may cap it (see [`PlayerState::volume`])
+    requested_volume: f32,
+    /// Schedule rule in effect
+    schedule: Option<ScheduleRule>,
+}
+
+impl PlayerState {
+    /// Volume to play at, the requested one capped by the schedule rule in effect
+    fn volume(&self) -> f32 {
+        let max_volume = self.schedule.as_ref().and_then(|rule| rule.max_volume);
+        max_volume.map_or(self.requested_volume, |max| self.requested_volume.min(max))
+    }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 enum BroadcastEvent {
-    UpdateQueue,
+    /// Send the whole queue, only to a client that just connected
+    SendQueue,
+    /// Queue patch or whole queue, already serialized
+    Queue(Arc<str>),
     UpdatePlayer,
+    UpdateReactions,
+    UpdatePlaylists,
+    Snackbar(String),
+    /// Close the connection, the server is going down
+    Shutdown,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 enum HandlerEvent {
     UpdateQueue,
+    UpdateReactions,
+    UpdatePlaylists,
     Pause,
     Resume,
     Skip,
     SetVolume,
+    SetAudioOutput,
+    /// Play an audio file over the ducked music
+    Announce(PathBuf),
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone)]
 enum PlayerEvent {
     Pause,
     Resume,
     Skip,
     SetVolume,
+    SetAudioOutput,
+    Announce(PathBuf),
+    /// Fade out and stop, the player returns once the current song is recorded
+    Shutdown,
+}
+
+impl AppState<'_> {
+    /// Output chosen at runtime, or the one from the config
+    fn audio_output<'a>(&'a self, config: &'a Config) -> Option<&'a AudioOutput> {
+        self.settings
+            .audio_output
+            .as_ref()
+            .or(config.player.audio_output.as_ref())
+    }
 }
 
 impl Default for PlayerState {
     fn default() -> Self {
         PlayerState {
             playing: true,
-            volume: 0.7,
+            requested_volume: 0.7,
+            schedule: None,
+        }
+    }
+}
+
+/// Errors that keep the backend from starting
+#[derive(Debug)]
+enum StartupError {
+    Logger(String),
+    Config(anyhow::Error),
+    Bind { addr: String, error: io::Error },
+    Signals(io::Error),
+}
+
+impl fmt::Display for StartupError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StartupError::Logger(error) => write!(f, "Failed to set up logging: {error}"),
+            StartupError::Config(error) => write!(f, "{error}"),
+            StartupError::Bind { addr, error } if error.kind() == io::ErrorKind::AddrInUse => {
+                write!(
+                    f,
+                    "Failed to listen on {addr}: address already in use, \
+                     is another instance running? (the address is set with `http.listen`)"
+                )
+            }
+            StartupError::Bind { addr, error } => {
+                write!(
+                    f,
+                    "Failed to listen on {addr}: {error} (the address is set with `http.listen`)"
+                )
+            }
+            StartupError::Signals(error) => {
+                write!(f, "Failed to register signal handlers: {error}")
+            }
         }
     }
 }
 
-fn main() {
+impl std::error::Error for StartupError {}
+
+fn init_logger() -> Result<(), StartupError> {
     if connected_to_journal() {
-        JournalLog::new().unwrap().install().unwrap();
+        JournalLog::new()
+            .map_err(|error| StartupError::Logger(error.to_string()))?
+            .install()
+            .map_err(|error| StartupError::Logger(error.to_string()))?;
     } else {
-        let config = ConfigBuilder::new()
-            .set_time_format_rfc2822()
-            .set_time_offset_to_local()
-            .unwrap()
-            .build();
+        let mut config = ConfigBuilder::new();
+        config.set_time_format_rfc2822();
+        // Fall back to UTC when the local offset can't be determined
+        let _ = config.set_time_offset_to_local();
         TermLogger::init(
             LevelFilter::Trace,
-            config,
+            config.build(),
             TerminalMode::Mixed,
             ColorChoice::Auto,
         )
-        .unwrap();
+        .map_err(|error| StartupError::Logger(error.to_string()))?;
     }
     log::set_max_level(LevelFilter::Info);
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let mut args = std::env::args().skip(1);
+    if let Some(command) = args.next() {
+        return match command.as_str() {
+            "stats" => stats::cli(args),
+            _ => {
+                eprintln!("Unknown command {command:?}, the only one is `stats`");
+                ExitCode::FAILURE
+            }
+        };
+    }
+
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error @ StartupError::Logger(_)) => {
+            eprintln!("{error}");
+            ExitCode::FAILURE
+        }
+        Err(error) => {
+            error!("{error}");
+            ExitCode::FAILURE
+        }
+    }
+}
 
-    let state = Mutex::new(AppState::default());
+fn run() -> Result<(), StartupError> {
+    init_logger()?;
+    let config = Config::load().map_err(StartupError::Config)?;
+
+    let mut state = AppState {
+        history: History::load(&config.data_dir),
+        settings: Settings::load(&config.data_dir),
+        users: Users::load(&config.data_dir),
+        playlists: Playlists::load(&config.data_dir),
+        ..AppState::default()
+    };
+    restore_queue(&mut state, &config);
+    state.queue_sync = QueueSync::new(handler::queue_json(&state, &config));
+    let state = Mutex::new(state);
     let event_listeners = Mutex::new(Vec::new());
     let (broadcast_tx, broadcast_rx) = channel::unbounded::<BroadcastEvent>();
     let (handler_event_tx, handler_event_rx) = channel::unbounded::<HandlerEvent>();
     let (player_event_tx, player_event_rx) = channel::unbounded::<PlayerEvent>();
+    let (queue_update_tx, queue_update_rx) = channel::unbounded::<()>();
 
-    let server = block_on(TcpListener::bind("0.0.0.0:9001")).unwrap();
+    let addr = &config.http.listen;
+    let server = block_on(TcpListener::bind(addr)).map_err(|error| StartupError::Bind {
+        addr: addr.clone(),
+        error,
+    })?;
+    info!("Listening on {addr}");
+    let mut signals = Signals::new([Signal::Term, Signal::Int]).map_err(StartupError::Signals)?;
 
     let ex = Executor::new();
-    let task1 = player(&state, player_event_rx, broadcast_tx.clone());
-    let task2 = process_queue(&state, handler_event_tx.clone());
+    ex.spawn(load_library(&state, config.library.dirs.clone()))
+        .detach();
+    ex.spawn(health::notify_systemd()).detach();
+    ex.spawn(store::save_files(&state)).detach();
+    ex.spawn(scheduler(&state, &config, handler_event_tx.clone()))
+        .detach();
+    ex.spawn(playlists::fallback(
+        &state,
+        &config,
+        handler_event_tx.clone(),
+    ))
+    .detach();
+    ex.spawn(broadcast_queue(
+        &state,
+        &config,
+        queue_update_rx,
+        broadcast_tx.clone(),
+    ))
+    .detach();
+    let task1 = player(&state, &config, player_event_rx, handler_event_tx.clone());
+    let task2 = async {
+        process_queue(&state, &config, handler_event_tx.clone()).await;
+    };
     let task3 = async {
         let mut incoming = server.incoming();
         loop {
             match incoming.next().await {
                 Some(Ok(stream)) => {
-                    let (tx, rx) = channel::unbounded();
-                    let _ = tx.send(BroadcastEvent::UpdatePlayer).await;
-                    let _ = tx.send(BroadcastEvent::UpdateQueue).await;
-                    event_listeners.lock().await.push(tx);
                     ex.spawn(async {
-                        if let Err(error) =
-                            handle(stream, &state, rx, handler_event_tx.clone()).await
+                        if let Err(error) = serve(
+                            stream,
+                            &state,
+                            &config,
+                            &event_listeners,
+                            handler_event_tx.clone(),
+                        )
+                        .await
                         {
                             error!("Error while handling socket: {error}");
                         }
@@ -118,38 +316,32 @@ fn main() {
                 }
                 Some(Err(error)) => {
                     error!("Error listening socket: {error}");
-                    break Err(error);
+                    break;
                 }
                 None => {
-                    break Ok(());
+                    break;
                 }
             }
         }
     };
     let task4 = async {
-        loop {
-            let event = match broadcast_rx.recv().await {
-                Ok(event) => event,
-                Err(err) => {
-                    break Result::<Infallible, RecvError>::Err(err);
-                }
-            };
+        while let Ok(event) = broadcast_rx.recv().await {
             for listener in event_listeners.lock().await.iter() {
-                let _ = listener.send(event).await;
+                let _ = listener.send(event.clone()).await;
             }
         }
     };
     let task5 = async {
-        loop {
-            let event = match handler_event_rx.recv().await {
-                Ok(event) => event,
-                Err(err) => {
-                    break Result::<Infallible, RecvError>::Err(err);
-                }
-            };
+        while let Ok(event) = handler_event_rx.recv().await {
             match event {
                 HandlerEvent::UpdateQueue => {
-                    let _ = broadcast_tx.send(BroadcastEvent::UpdateQueue).await;
+                    let _ = queue_update_tx.send(()).await;
+                }
+                HandlerEvent::UpdateReactions => {
+                    let _ = broadcast_tx.send(BroadcastEvent::UpdateReactions).await;
+                }
+                HandlerEvent::UpdatePlaylists => {
+                    let _ = broadcast_tx.send(BroadcastEvent::UpdatePlaylists).await;
                 }
                 HandlerEvent::Pause => {
                     state.lock().await.player.playing = false;
@@ -168,10 +360,84 @@ fn main() {
                     let _ = player_event_tx.send(PlayerEvent::SetVolume).await;
                     let _ = broadcast_tx.send(BroadcastEvent::UpdatePlayer).await;
                 }
+                HandlerEvent::SetAudioOutput => {
+                    let _ = player_event_tx.send(PlayerEvent::SetAudioOutput).await;
+                    let _ = broadcast_tx.send(BroadcastEvent::UpdatePlayer).await;
+                }
+                HandlerEvent::Announce(path) => {
+                    let _ = player_event_tx.send(PlayerEvent::Announce(path)).await;
+                }
+            }
+        }
+    };
+    let shutdown = async {
+        while let Some(signal) = signals.next().await {
+            match signal {
+                Ok(signal) => {
+                    info!("Received {signal:?}, shutting down");
+                    break;
+                }
+                Err(error) => error!("Error receiving signal: {error}"),
             }
         }
     };
 
-    let task = zip(zip(zip(zip(task1, task2), task3), task4), task5);
-    block_on(ex.run(task));
+    block_on(ex.run(async {
+        let mut task1 = pin!(task1);
+        // The other tasks stop here, the player keeps running to fade out
+        let player_stopped = async { Some((&mut task1).await) }
+            .or(async {
+                task2.or(task3).or(task4).or(task5).or(shutdown).await;
+                None
+            })
+            .await;
+        match player_stopped {
+            Some(result) => error!("Player stopped unexpectedly: {result:?}"),
+            None => {
+                let _ = player_event_tx.send(PlayerEvent::Shutdown).await;
+                async {
+                    let _ = task1.await;
+                }
+                .or(timeout(SHUTDOWN_TIMEOUT))
+                .await;
+            }
+        }
+
+        let listeners = take(&mut *event_listeners.lock().await);
+        for listener in &listeners {
+            let _ = listener.send(BroadcastEvent::Shutdown).await;
+        }
+        // A connection is done once it dropped its receiver
+        async {
+            while !listeners.iter().all(Sender::is_closed) {
+                Timer::after(Duration::from_millis(50)).await;
+            }
+        }
+        .or(timeout(SHUTDOWN_TIMEOUT))
+        .await;
+
+        store::flush(&state).await;
+        let mut state = state.lock().await;
+        if let Err(error) = save_queue(&state, &config.data_dir) {
+            error!("Failed to save the queue: {error}");
+        }
+        // Dropping the fetch tasks kills their yt-dlp processes
+        drop(take(&mut state.queue));
+    }));
+
+    info!("Bye");
+    Ok(())
+}
+
+/// Put the songs saved on the last shutdown back in the queue
+fn restore_queue(state: &mut AppState, config: &Config) {
+    let songs = take_saved_queue(&config.data_dir);
+    if !songs.is_empty() {
+        info!("Restoring {} saved songs", songs.len());
+        state.queue.restore(songs, config.ytdlp.timeout());
+    }
+}
+
+async fn timeout(duration: Duration) {
+    Timer::after(duration).await;
 }
@@ -0,0 +1,188 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{
+        Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+use crate::{AppState, song_queue::QueueEntry};
+
+/// Process wide counters, rendered in the Prometheus text format on `/metrics`
+pub static METRICS: Metrics = Metrics::new();
+
+pub struct Metrics {
+    pub songs_requested: AtomicU64,
+    pub songs_played: AtomicU64,
+    pub songs_skipped: AtomicU64,
+    /// Songs that could not be fetched or played
+    pub songs_failed: AtomicU64,
+    pub vlc_errors: AtomicU64,
+    pub clients: AtomicU64,
+    pub ytdlp_duration: Histogram,
+    /// yt-dlp failures by [`YtdlpError::kind`](crate::yt_dlp::YtdlpError::kind)
+    ytdlp_failures: Mutex<BTreeMap<&'static str, u64>>,
+}
+
+/// Upper bounds (seconds) of the yt-dlp latency buckets
+const DURATION_BUCKETS: [f64; 9] = [0.5, 1.0, 2.0, 3.0, 5.0, 10.0, 20.0, 30.0, 60.0];
+
+pub struct Histogram {
+    /// Non-cumulative count of each bucket, the last one is `+Inf`
+    buckets: [AtomicU64; DURATION_BUCKETS.len() + 1],
+    sum_ms: AtomicU64,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Metrics {
+            songs_requested: AtomicU64::new(0),
+            songs_played: AtomicU64::new(0),
+            songs_skipped: AtomicU64::new(0),
+            songs_failed: AtomicU64::new(0),
+            vlc_errors: AtomicU64::new(0),
+            clients: AtomicU64::new(0),
+            ytdlp_duration: Histogram::new(),
+            ytdlp_failures: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn ytdlp_failed(&self, kind: &'static str) {
+        let mut failures = self.ytdlp_failures.lock().unwrap();
+        *failures.entry(kind).or_default() += 1;
+    }
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Histogram {
+            buckets: [const { AtomicU64::new(0) }; DURATION_BUCKETS.len() + 1],
+            sum_ms: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        let index = DURATION_BUCKETS
+            .iter()
+            .position(|&bound| secs <= bound)
+            .unwrap_or(DURATION_BUCKETS.len());
+        self.buckets[index].fetch_add(1, Ordering::Relaxed);
+        self.sum_ms
+            .fetch_add(duration.as_millis() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Counts a connected WebSocket client until dropped, also when the connection future is
+pub struct ClientGuard(());
+
+impl ClientGuard {
+    pub fn new() -> Self {
+        METRICS.clients.fetch_add(1, Ordering::Relaxed);
+        ClientGuard(())
+    }
+}
+
+impl Drop for ClientGuard {
+    fn drop(&mut self) {
+        METRICS.clients.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+pub fn inc(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Body of the `/metrics` response
+pub fn render(state: &AppState) -> String {
+    let mut out = String::new();
+    let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+    let mut metric = |name: &str, kind: &str, help: &str, value: u64| {
+        let _ = writeln!(
+            out,
+            "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}"
+        );
+    };
+    metric(
+        "cafe_songs_requested_total",
+        "counter",
+        "Songs requested by clients",
+        get(&METRICS.songs_requested),
+    );
+    metric(
+        "cafe_songs_played_total",
+        "counter",
+        "Songs that started playing",
+        get(&METRICS.songs_played),
+    );
+    metric(
+        "cafe_songs_skipped_total",
+        "counter",
+        "Songs skipped before the end",
+        get(&METRICS.songs_skipped),
+    );
+    metric(
+        "cafe_songs_failed_total",
+        "counter",
+        "Songs that could not be fetched or played",
+        get(&METRICS.songs_failed),
+    );
+    metric(
+        "cafe_vlc_errors_total",
+        "counter",
+        "Errors reported by VLC",
+        get(&METRICS.vlc_errors),
+    );
+    metric(
+        "cafe_connected_clients",
+        "gauge",
+        "WebSocket clients currently connected",
+        get(&METRICS.clients),
+    );
+
+    let _ = writeln!(
+        out,
+        "# HELP cafe_queue_length Songs in the queue by state\n# TYPE cafe_queue_length gauge"
+    );
+    for name in QueueEntry::STATES {
+        let count = state
+            .queue
+            .iter()
+            .filter(|item| item.entry.state() == name)
+            .count();
+        let _ = writeln!(out, "cafe_queue_length{{state=\"{name}\"}} {count}");
+    }
+
+    let _ = writeln!(
+        out,
+        "# HELP cafe_ytdlp_failures_total yt-dlp calls that failed, by kind\n# TYPE cafe_ytdlp_failures_total counter"
+    );
+    for (kind, count) in METRICS.ytdlp_failures.lock().unwrap().iter() {
+        let _ = writeln!(out, "cafe_ytdlp_failures_total{{kind=\"{kind}\"}} {count}");
+    }
+
+    let histogram = &METRICS.ytdlp_duration;
+    let _ = writeln!(
+        out,
+        "# HELP cafe_ytdlp_duration_seconds Time taken by yt-dlp calls\n# TYPE cafe_ytdlp_duration_seconds histogram"
+    );
+    let mut cumulative = 0;
+    for (index, bucket) in histogram.buckets.iter().enumerate() {
+        cumulative += get(bucket);
+        let bound = DURATION_BUCKETS
+            .get(index)
+            .map_or("+Inf".to_owned(), |bound| bound.to_string());
+        let _ = writeln!(
+            out,
+            "cafe_ytdlp_duration_seconds_bucket{{le=\"{bound}\"}} {cumulative}"
+        );
+    }
+    let sum = get(&histogram.sum_ms) as f64 / 1000.0;
+    let _ = writeln!(out, "cafe_ytdlp_duration_seconds_sum {sum}");
+    let _ = writeln!(out, "cafe_ytdlp_duration_seconds_count {cumulative}");
+
+    out
+}
@@ -1,62 +1,326 @@
-use std::convert::Infallible;
-use std::time::Duration;
+use std::cell::Cell;
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
 
-use log::{error, info};
+use chrono::Local;
+use log::{error, info, warn};
 use smol::{
     Timer,
-    channel::{Receiver, RecvError, Sender},
+    channel::{self, Receiver, RecvError, Sender},
     future::FutureExt,
     lock::Mutex,
 };
 use vlc::MediaPlayerAudioEx as _;
 
-use crate::{AppState, BroadcastEvent, PlayerEvent};
+use crate::{
+    AppState, HandlerEvent, NowPlaying, PlayerEvent,
+    audio_output::{self, AudioOutput},
+    config::{Config, PlayerConfig},
+    health::HEALTH,
+    history::{HistoryRecord, unix_now},
+    metrics::{self, METRICS},
+    reactions::Reactions,
+    song_queue::Song,
+    tts,
+    yt_dlp::MediaFormat,
+};
+
+const SHUTDOWN_FADE: Duration = Duration::from_millis(1500);
+/// Speech is written to this file in the data directory
+const TTS_FILE: &str = "tts.wav";
+
+/// Something to play over the music
+enum Announcement {
+    File(PathBuf),
+    /// Text to speak with the TTS program, `done` is dropped once it was spoken
+    Speech {
+        text: String,
+        done: Sender<()>,
+    },
+}
 
 // expected input range: 0.0 ~ 1.0
 fn adjust_volume(volume: f32) -> i32 {
     (volume * 100.) as i32
 }
 
+/// Formats with audio, best candidate first
+fn rank_formats<'a>(formats: &'a [MediaFormat], config: &PlayerConfig) -> Vec<&'a MediaFormat> {
+    let has = |codec: &Option<String>| codec.as_deref().is_some_and(|s| s != "none");
+    let mut formats = formats
+        .iter()
+        .filter(|m| has(&m.acodec))
+        .filter(|m| config.allow_muxed || !has(&m.vcodec))
+        .collect::<Vec<_>>();
+
+    let codec_rank = |m: &MediaFormat| {
+        let acodec = m.acodec.as_deref().unwrap_or_default();
+        config
+            .preferred_codecs
+            .iter()
+            .position(|codec| acodec.starts_with(codec.as_str()))
+            .unwrap_or(config.preferred_codecs.len())
+    };
+    let over_limit = |m: &MediaFormat| {
+        config
+            .max_bitrate_kbps
+            .is_some_and(|limit| m.bitrate().is_some_and(|bitrate| bitrate > limit))
+    };
+
+    formats.sort_by(|a, b| {
+        let key = |m: &MediaFormat| (has(&m.vcodec), over_limit(m), codec_rank(m));
+        key(a).cmp(&key(b)).then_with(|| {
+            if over_limit(a) {
+                // Both are over the limit, use as little bandwidth as possible
+                a.bitrate()
+                    .unwrap_or(f32::MAX)
+                    .total_cmp(&b.bitrate().unwrap_or(f32::MAX))
+            } else {
+                let quality =
+                    |m: &MediaFormat| (m.quality.unwrap_or(-10.0), m.bitrate().unwrap_or(0.0));
+                let (a, b) = (quality(a), quality(b));
+                b.0.total_cmp(&a.0).then(b.1.total_cmp(&a.1))
+            }
+        })
+    });
+    formats
+}
+
+#[derive(Debug)]
+enum VlcInitError {
+    Instance,
+    MediaPlayer,
+}
+
+impl fmt::Display for VlcInitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VlcInitError::Instance => write!(
+                f,
+                "Failed to create VLC instance, check that libvlc and its plugins are installed"
+            ),
+            VlcInitError::MediaPlayer => write!(f, "Failed to create VLC media player"),
+        }
+    }
+}
+
+fn init_vlc() -> Result<(vlc::Instance, vlc::MediaPlayer), VlcInitError> {
+    let instance = vlc::Instance::new().ok_or(VlcInitError::Instance)?;
+    let player = vlc::MediaPlayer::new(&instance).ok_or(VlcInitError::MediaPlayer)?;
+    Ok((instance, player))
+}
+
+/// Initialise VLC, retrying with backoff until it works (e.g. the audio server is not up yet at boot)
+///
+/// Returns `None` if a shutdown is requested in the meantime
+async fn init_vlc_with_retry(
+    player_event_rx: &Receiver<PlayerEvent>,
+) -> Result<Option<(vlc::Instance, vlc::MediaPlayer)>, RecvError> {
+    const MAX_DELAY: Duration = Duration::from_secs(30);
+    let mut delay = Duration::from_secs(1);
+    loop {
+        match init_vlc() {
+            Ok(vlc) => return Ok(Some(vlc)),
+            Err(error) => error!("{error}, retrying in {}s", delay.as_secs()),
+        }
+        // Other events can be dropped, the player state is read from `AppState` once VLC is up
+        let shutdown = async {
+            while !matches!(player_event_rx.recv().await?, PlayerEvent::Shutdown) {}
+            Ok(true)
+        }
+        .or(async {
+            Timer::after(delay).await;
+            Ok(false)
+        })
+        .await?;
+        if shutdown {
+            return Ok(None);
+        }
+        delay = (delay * 2).min(MAX_DELAY);
+    }
+}
+
+fn apply_audio_output(player: &vlc::MediaPlayer, output: &AudioOutput) {
+    let device = output.device.as_deref().unwrap_or("default");
+    if audio_output::apply(player, output) {
+        info!("Audio output set to {} ({device})", output.module);
+    } else {
+        error!("Unknown audio output module: {}", output.module);
+    }
+}
+
+/// Volume chosen by the user, on VLC's scale
+async fn user_volume(state: &Mutex<AppState<'_>>) -> i32 {
+    adjust_volume(state.lock().await.player.volume().clamp(0.0, 1.0))
+}
+
+fn set_volume(player: &vlc::MediaPlayer, volume: i32) {
+    if player.set_volume(volume).is_err() {
+        error!("Failed to set volume: {}", volume);
+    }
+}
+
+/// Ramp the volume from `from` to `to` (VLC scale) over `duration`
+///
+/// Only VLC's volume changes, `PlayerState` is left alone
+async fn fade(player: &vlc::MediaPlayer, from: i32, to: i32, duration: Duration) {
+    const STEP: Duration = Duration::from_millis(50);
+    let steps = (duration.as_millis() / STEP.as_millis()) as i32;
+    for step in 1..=steps {
+        set_volume(player, from + (to - from) * step / steps);
+        Timer::after(STEP).await;
+    }
+    set_volume(player, to);
+}
+
+/// Run `fade` unless a player event comes in first, that event is returned to be handled next
+///
+/// Pausing, resuming and skipping take effect right away instead of queueing behind a fade.
+async fn fade_or_event(
+    fade: impl Future<Output = ()>,
+    player_event_rx: &Receiver<PlayerEvent>,
+) -> Result<Option<PlayerEvent>, RecvError> {
+    async {
+        fade.await;
+        Ok(None)
+    }
+    .or(async { player_event_rx.recv().await.map(Some) })
+    .await
+}
+
+/// Wait until the player leaves the opening state
+///
+/// Returns `false` if the media failed to open
+async fn wait_until_started(player: &vlc::MediaPlayer) -> bool {
+    const OPEN_TIMEOUT: Duration = Duration::from_secs(15);
+    let deadline = Instant::now() + OPEN_TIMEOUT;
+    loop {
+        HEALTH.player.tick();
+        match player.state() {
+            vlc::State::Error => return false,
+            vlc::State::NothingSpecial | vlc::State::Opening | vlc::State::Buffering => {
+                if Instant::now() >= deadline {
+                    player.stop();
+                    return false;
+                }
+                Timer::after(Duration::from_millis(100)).await;
+            }
+            vlc::State::Playing | vlc::State::Paused | vlc::State::Stopped | vlc::State::Ended => {
+                return true;
+            }
+        }
+    }
+}
+
 pub async fn player(
     state: &Mutex<AppState<'_>>,
+    config: &Config,
     player_event_rx: Receiver<PlayerEvent>,
-    broadcast_tx: Sender<BroadcastEvent>,
-) -> Result<Infallible, RecvError> {
-    let vlc_instance = vlc::Instance::new().expect("Failed to create VLC instance");
-    let player = vlc::MediaPlayer::new(&vlc_instance).expect("Failed to create VLC MediaPlayer");
+    handler_event_tx: Sender<HandlerEvent>,
+) -> Result<(), RecvError> {
+    let Some((vlc_instance, player)) = init_vlc_with_retry(&player_event_rx).await? else {
+        return Ok(());
+    };
+    HEALTH.vlc_ready.store(true, Ordering::Relaxed);
+
+    // Plays announcements over the music
+    let announcer = vlc::MediaPlayer::new(&vlc_instance);
+    if announcer.is_none() {
+        error!("Failed to create VLC MediaPlayer for announcements");
+    }
 
     {
         let state = state.lock().await;
-        player.set_pause(!state.player.playing);
-        let volume = adjust_volume(state.player.volume);
-        if player.set_volume(volume).is_err() {
-            error!("Failed to set volume: {}", volume);
+        if let Some(output) = state.audio_output(config) {
+            apply_audio_output(&player, output);
+            if let Some(announcer) = &announcer {
+                apply_audio_output(announcer, output);
+            }
         }
+        player.set_pause(!state.player.playing);
+        set_volume(&player, adjust_volume(state.player.volume()));
     }
 
+    let skipped = Cell::new(false);
+    let stopping = Cell::new(false);
+    // Music is turned down while an announcement plays
+    let ducked = Cell::new(false);
+    let (announce_tx, announce_rx) = channel::unbounded::<Announcement>();
+
+    // Volume the music should be at
+    let target_volume = async || {
+        let volume = user_volume(state).await;
+        if ducked.get() {
+            (volume as f32 * config.announcements.duck_volume.clamp(0.0, 1.0)) as i32
+        } else {
+            volume
+        }
+    };
+
     let task1 = async {
+        // Event that interrupted a fade
+        let mut next = None;
         loop {
-            let event = match player_event_rx.recv().await {
-                Ok(event) => event,
-                Err(err) => return Err(err),
+            let event = match next.take() {
+                Some(event) => event,
+                None => player_event_rx.recv().await?,
             };
             match event {
                 PlayerEvent::Pause => {
+                    if player.is_playing() {
+                        let fade = fade(&player, player.get_volume(), 0, config.player.fade());
+                        next = fade_or_event(fade, &player_event_rx).await?;
+                    }
                     player.set_pause(true);
+                    set_volume(&player, target_volume().await);
                 }
                 PlayerEvent::Resume => {
-                    player.set_pause(false);
+                    if player.state() == vlc::State::Paused {
+                        set_volume(&player, 0);
+                        player.set_pause(false);
+                        let target = target_volume().await;
+                        let fade = fade(&player, 0, target, config.player.fade());
+                        next = fade_or_event(fade, &player_event_rx).await?;
+                    } else {
+                        player.set_pause(false);
+                    }
                 }
                 PlayerEvent::Skip => {
+                    skipped.set(true);
+                    if player.is_playing() {
+                        let fade = fade(&player, player.get_volume(), 0, config.player.fade());
+                        next = fade_or_event(fade, &player_event_rx).await?;
+                    }
                     player.stop();
+                    set_volume(&player, target_volume().await);
                 }
                 PlayerEvent::SetVolume => {
-                    let volume = state.lock().await.player.volume;
-                    let volume = adjust_volume(volume.clamp(0.0, 1.0));
-                    if player.set_volume(volume).is_err() {
-                        error!("Failed to set volume: {}", volume);
+                    set_volume(&player, target_volume().await);
+                }
+                PlayerEvent::SetAudioOutput => {
+                    let state = state.lock().await;
+                    match state.audio_output(config) {
+                        Some(output) => {
+                            apply_audio_output(&player, output);
+                            if let Some(announcer) = &announcer {
+                                apply_audio_output(announcer, output);
+                            }
+                        }
+                        None => info!("VLC's default audio output is used after a restart"),
                     }
                 }
+                PlayerEvent::Announce(path) => {
+                    let _ = announce_tx.send(Announcement::File(path)).await;
+                }
+                PlayerEvent::Shutdown => {
+                    stopping.set(true);
+                    if player.is_playing() {
+                        fade(&player, player.get_volume(), 0, SHUTDOWN_FADE).await;
+                    }
+                    player.stop();
+                }
             }
         }
     };
@@ -66,11 +330,23 @@ pub async fn player(
         loop {
             let info = {
                 loop {
+                    HEALTH.player.tick();
+                    if stopping.get() {
+                        state.lock().await.now_playing = None;
+                        return Ok(());
+                    }
                     {
                         let mut state = state.lock().await;
-                        if let Some(info) = state.queue.try_pop().await {
-                            state.now_playing = info.clone();
-                            break info;
+                        if let Some(next) = state.queue.try_pop(config.queue.head_policy).await {
+                            let next = next.map(|(song, requester)| (song, requester.user()));
+                            state.now_playing = next.clone().map(|(song, requester)| NowPlaying {
+                                song,
+                                requester,
+                                reactions: Reactions::default(),
+                                position: 0,
+                                started_at: None,
+                            });
+                            break next;
                         }
                     }
                     Timer::after(Duration::from_millis(200)).await;
@@ -78,49 +354,156 @@ pub async fn player(
             };
 
             if queue_was_not_empty || info.is_some() {
-                let _ = broadcast_tx.send(BroadcastEvent::UpdateQueue).await;
+                let _ = handler_event_tx.send(HandlerEvent::UpdateQueue).await;
             }
 
             queue_was_not_empty = info.is_some();
 
-            if let Some(info) = info {
-                let format = info
-                    .formats
-                    .iter()
-                    .filter(|m| m.acodec.clone().is_some_and(|s| s != "none"))
-                    .filter(|m| m.vcodec.clone().is_none_or(|s| s == "none"))
-                    .reduce(|acc, e| {
-                        std::cmp::max_by_key(acc, e, |v| v.quality.unwrap_or(-10.0) as i32)
-                    });
-
-                let format = match format {
-                    Some(format) => format,
-                    None => {
-                        error!("No usable format when playing id: {}", info.id);
-                        continue;
+            if let Some((song, requester)) = info {
+                let (candidates, id) = match &song {
+                    Song::Youtube(info) => {
+                        let formats = rank_formats(&info.formats, &config.player);
+                        if formats.is_empty() {
+                            error!("No usable format when playing id: {}", info.id);
+                            continue;
+                        }
+                        let candidates = formats
+                            .into_iter()
+                            .map(|format| {
+                                let media = vlc::Media::new_location(&vlc_instance, &format.url);
+                                (media, format.format_id.as_deref().unwrap_or("?"))
+                            })
+                            .collect::<Vec<_>>();
+                        (candidates, info.id.clone())
                     }
+                    Song::Local(track) => (
+                        vec![(vlc::Media::new_path(&vlc_instance, &track.path), "file")],
+                        track.path.display().to_string(),
+                    ),
                 };
 
-                let Some(media) = vlc::Media::new_location(&vlc_instance, &format.url) else {
-                    error!("Failed to create new vlc Media");
-                    continue;
+                // From here a skip is for this song, even while its title is spoken
+                skipped.set(false);
+
+                // Between the tracks, the song starts once its title was spoken
+                let (playing, nickname) = {
+                    let state = state.lock().await;
+                    let nickname = requester
+                        .and_then(|id| state.users.nickname(id))
+                        .map(str::to_owned);
+                    (state.player.playing, nickname)
                 };
+                if playing && config.tts.is_active(Local::now().naive_local()) {
+                    let text = match &nickname {
+                        Some(nickname) => {
+                            format!("Next up: {}, requested by {nickname}", song.title())
+                        }
+                        None => format!("Next up: {}", song.title()),
+                    };
+                    let (done, spoken) = channel::bounded(1);
+                    let _ = announce_tx.send(Announcement::Speech { text, done }).await;
+                    // Synthesis and speech take a while, the heartbeat keeps going meanwhile
+                    while !skipped.get() && !stopping.get() {
+                        HEALTH.player.tick();
+                        // `recv` fails once `done` is dropped
+                        let spoken = async { spoken.recv().await.is_err() }
+                            .or(async {
+                                Timer::after(Duration::from_millis(200)).await;
+                                false
+                            })
+                            .await;
+                        if spoken {
+                            break;
+                        }
+                    }
+                }
+                if stopping.get() {
+                    // Keep `now_playing` so the song is saved with the queue
+                    return Ok(());
+                }
+                if skipped.get() {
+                    info!("Skipped before it started (id: {id})");
+                    metrics::inc(&METRICS.songs_skipped);
+                    continue;
+                }
 
-                player.set_media(&media);
-                player.set_time(0);
-                if player.play().is_err() {
-                    error!("Failed to start playing");
+                let started_at = unix_now();
+                let mut started = false;
+                for (media, format_id) in candidates {
+                    let Some(media) = media else {
+                        error!("Failed to create new vlc Media (format: {format_id})");
+                        continue;
+                    };
+
+                    player.set_media(&media);
+                    player.set_time(0);
+                    if player.play().is_err() {
+                        error!("Failed to start playing (format: {format_id})");
+                        metrics::inc(&METRICS.vlc_errors);
+                        continue;
+                    }
+
+                    if wait_until_started(&player).await {
+                        info!("Start playing song (id: {id}, format: {format_id})");
+                        started = true;
+                        break;
+                    }
+                    warn!("Failed to open format {format_id} of {id}, trying the next one");
+                    metrics::inc(&METRICS.vlc_errors);
                 }
 
-                info!("Start playing song (id: {})", info.id);
+                if !started {
+                    error!("No format could be played (id: {id})");
+                    metrics::inc(&METRICS.songs_failed);
+                    continue;
+                }
+                metrics::inc(&METRICS.songs_played);
 
+                let length_ms = song.duration().map(|secs| i64::from(secs) * 1000);
+                let fade_end_ms = config.player.fade_end().as_millis() as i64;
+                let mut played_ms = 0;
                 loop {
+                    HEALTH.player.tick();
+                    if let Some(time) = player.get_time() {
+                        played_ms = played_ms.max(time);
+                        let position = u32::try_from(time / 1000).unwrap_or_default();
+                        // Only set when playing starts or resumes, so it stays the same in between
+                        let started_at = player
+                            .is_playing()
+                            .then(|| unix_now().saturating_sub(time as u64 / 1000));
+                        let paused_or_resumed = match &mut state.lock().await.now_playing {
+                            Some(now_playing) => {
+                                now_playing.position = position;
+                                let changed =
+                                    now_playing.started_at.is_some() != started_at.is_some();
+                                if changed {
+                                    now_playing.started_at = started_at;
+                                }
+                                changed
+                            }
+                            None => false,
+                        };
+                        if paused_or_resumed {
+                            let _ = handler_event_tx.send(HandlerEvent::UpdateQueue).await;
+                        }
+                        // Fade out over the last `fade_end_ms` of the song
+                        if let Some(length_ms) = length_ms
+                            && fade_end_ms > 0
+                            && length_ms - time < fade_end_ms
+                            && player.is_playing()
+                        {
+                            let remaining = (length_ms - time).max(0);
+                            let target = i64::from(target_volume().await);
+                            set_volume(&player, (target * remaining / fade_end_ms) as i32);
+                        }
+                    }
                     match player.state() {
                         vlc::State::Ended | vlc::State::Stopped => {
                             break;
                         }
                         vlc::State::Error => {
                             error!("MediaPlayer ended with an error");
+                            metrics::inc(&METRICS.vlc_errors);
                             break;
                         }
                         _ => {
@@ -129,11 +512,90 @@ pub async fn player(
                     }
                 }
 
+                // Undo the fade out for the next song
+                set_volume(&player, target_volume().await);
+                if skipped.get() {
+                    metrics::inc(&METRICS.songs_skipped);
+                }
+                let played = u32::try_from(played_ms / 1000).unwrap_or_default();
+                let mut state = state.lock().await;
+                let requester = requester.and_then(|id| state.users.get(id));
+                let record = HistoryRecord {
+                    interrupted: stopping.get(),
+                    reactions: state
+                        .now_playing
+                        .as_ref()
+                        .map(|now_playing| now_playing.reactions.counts())
+                        .unwrap_or_default(),
+                    ..HistoryRecord::new(&song, requester, started_at, played, skipped.get())
+                };
+                let write = state.history.push(record);
+                drop(state);
+                write.await;
+
                 info!("Finished playing song");
+                if stopping.get() {
+                    // Keep `now_playing` so the interrupted song is saved with the queue
+                    return Ok(());
+                }
             }
             Timer::after(Duration::from_millis(200)).await;
         }
     };
 
-    task1.or(task2).await
+    let task3 = async {
+        // Overwritten by each speech. Not in the shared temp directory,
+        // where another user could put a symlink in its place
+        let speech_file = config.data_dir.join(TTS_FILE);
+        while let Ok(announcement) = announce_rx.recv().await {
+            let Some(announcer) = &announcer else {
+                continue;
+            };
+            // The player waits for a speech until `_done` is dropped at the end of the iteration
+            let (path, _done) = match announcement {
+                Announcement::File(path) => (path, None),
+                Announcement::Speech { text, done } => {
+                    if let Err(error) = smol::fs::create_dir_all(&config.data_dir).await {
+                        error!("Failed to create {}: {error}", config.data_dir.display());
+                        continue;
+                    }
+                    if let Err(error) = tts::synthesize(&config.tts, &text, &speech_file).await {
+                        error!("Failed to speak {text:?}: {error}");
+                        continue;
+                    }
+                    (speech_file.clone(), Some(done))
+                }
+            };
+            let Some(media) = vlc::Media::new_path(&vlc_instance, &path) else {
+                error!("Failed to create vlc Media for {}", path.display());
+                continue;
+            };
+            info!("Announcement: {}", path.display());
+
+            ducked.set(true);
+            let target = target_volume().await;
+            fade(&player, player.get_volume(), target, config.player.fade()).await;
+
+            set_volume(announcer, user_volume(state).await);
+            announcer.set_media(&media);
+            if announcer.play().is_ok() && wait_until_started(announcer).await {
+                while !matches!(
+                    announcer.state(),
+                    vlc::State::Ended | vlc::State::Stopped | vlc::State::Error
+                ) {
+                    Timer::after(Duration::from_millis(100)).await;
+                }
+            } else {
+                error!("Failed to play announcement {}", path.display());
+                metrics::inc(&METRICS.vlc_errors);
+            }
+
+            ducked.set(false);
+            let target = target_volume().await;
+            fade(&player, player.get_volume(), target, config.player.fade()).await;
+        }
+        Ok(())
+    };
+
+    task1.or(task2).or(task3).await
 }
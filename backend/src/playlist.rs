@@ -0,0 +1,112 @@
+//! Export the queue as a playlist, and read playlists to import
+
+use std::{
+    fmt::Write as _,
+    path::{Path, PathBuf},
+};
+
+use crate::song_queue::SavedSong;
+
+/// Most songs taken from a single import
+pub const MAX_IMPORT: usize = 500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    M3u,
+    /// A list of [`SavedSong`], like `queue.json`
+    Json,
+}
+
+/// A song to import, everything is fetched again
+#[derive(Debug, PartialEq, Eq)]
+pub enum ImportItem {
+    Link(String),
+    /// Only imported if the file is in the library
+    File(PathBuf),
+}
+
+impl Format {
+    pub fn parse(format: &str) -> Option<Format> {
+        match format {
+            "m3u" | "m3u8" => Some(Format::M3u),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Format::M3u => "m3u",
+            Format::Json => "json",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Format::M3u => "audio/x-mpegurl; charset=utf-8",
+            Format::Json => "application/json",
+        }
+    }
+}
+
+pub fn export(songs: &[SavedSong], format: Format) -> String {
+    match format {
+        Format::M3u => {
+            let mut m3u = "#EXTM3U\n".to_owned();
+            for song in songs {
+                let (location, title, duration) = match song {
+                    SavedSong::Link {
+                        url,
+                        title,
+                        duration,
+                        ..
+                    } => (url.clone(), title.as_deref().unwrap_or(url), *duration),
+                    SavedSong::Local(track) => (
+                        track.path.display().to_string(),
+                        track.title.as_str(),
+                        track.duration,
+                    ),
+                };
+                // -1 is M3U for an unknown duration
+                let duration = duration.map_or(-1, i64::from);
+                // A line break in the title would end the entry
+                let title = title.replace(['\r', '\n'], " ");
+                let _ = writeln!(m3u, "#EXTINF:{duration},{title}\n{location}");
+            }
+            m3u
+        }
+        Format::Json => serde_json::to_string_pretty(songs).unwrap_or_default(),
+    }
+}
+
+/// Songs of a JSON export, an M3U playlist, or a list of links one per line
+pub fn parse(content: &str) -> Result<Vec<ImportItem>, String> {
+    let content = content.trim_start_matches('\u{feff}').trim();
+    if content.starts_with('[') {
+        let songs = serde_json::from_str::<Vec<SavedSong>>(content)
+            .map_err(|error| format!("Invalid JSON playlist: {error}"))?;
+        let items = songs
+            .into_iter()
+            .map(|song| match song {
+                SavedSong::Link { url, .. } => ImportItem::Link(url),
+                SavedSong::Local(track) => ImportItem::File(track.path),
+            })
+            .collect();
+        return Ok(items);
+    }
+
+    // M3U comments and directives (`#EXTINF`, ...) are skipped, titles come from the fetch
+    let items = content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            if Path::new(line).is_absolute() {
+                ImportItem::File(PathBuf::from(line))
+            } else {
+                ImportItem::Link(line.to_owned())
+            }
+        })
+        .collect();
+    Ok(items)
+}
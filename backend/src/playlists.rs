@@ -0,0 +1,289 @@
+use std::{
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use smol::{Timer, channel::Sender, lock::Mutex};
+
+use crate::{
+    AppState, HandlerEvent,
+    config::Config,
+    handler::enqueue_link,
+    song_queue::{QueueEntry, Requester, SavedSong},
+    store::{PendingWrite, StoreFile},
+};
+
+const PLAYLISTS_FILE: &str = "playlists.json";
+const FALLBACK_INTERVAL: Duration = Duration::from_secs(1);
+/// Longest wait after fallback songs keep failing to fetch
+const MAX_FALLBACK_DELAY: Duration = Duration::from_secs(600);
+const MAX_NAME_LEN: usize = 64;
+
+/// Named playlists kept by the backend, saved to `playlists.json` in the data directory
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Playlists {
+    playlists: Vec<Playlist>,
+    next_id: u64,
+    /// Played when the queue runs empty
+    fallback: Option<u64>,
+    /// Index of the next fallback song
+    fallback_next: usize,
+    #[serde(skip)]
+    file: StoreFile,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Playlist {
+    pub id: u64,
+    pub name: String,
+    pub songs: Vec<SavedSong>,
+}
+
+#[derive(Debug)]
+pub enum PlaylistError {
+    NotFound,
+    InvalidName,
+    /// No song at this index
+    InvalidIndex,
+}
+
+impl PlaylistError {
+    /// Message that can be shown to the client
+    pub fn user_message(&self) -> &'static str {
+        match self {
+            PlaylistError::NotFound => "Playlist not found",
+            PlaylistError::InvalidName => "Playlist names can't be empty",
+            PlaylistError::InvalidIndex => "Song not found in the playlist",
+        }
+    }
+}
+
+impl Playlists {
+    pub fn load(data_dir: &Path) -> Playlists {
+        let (playlists, file) = StoreFile::load(data_dir, PLAYLISTS_FILE, "playlists");
+        Playlists { file, ..playlists }
+    }
+
+    /// Written by [`store::save_files`](crate::store::save_files), not while the state is locked
+    fn save(&mut self) {
+        self.file.mark_changed();
+    }
+
+    /// Content of `playlists.json` if it changed since the last call
+    pub fn take_changes(&mut self) -> Option<PendingWrite> {
+        let path = self.file.take_changed()?;
+        Some(PendingWrite::new(path, self))
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Playlist> {
+        self.playlists.iter().find(|playlist| playlist.id == id)
+    }
+
+    fn get_mut(&mut self, id: u64) -> Result<&mut Playlist, PlaylistError> {
+        self.playlists
+            .iter_mut()
+            .find(|playlist| playlist.id == id)
+            .ok_or(PlaylistError::NotFound)
+    }
+
+    pub fn fallback(&self) -> Option<u64> {
+        self.fallback
+    }
+
+    /// Returns the id of the new playlist
+    pub fn create(&mut self, name: &str) -> Result<u64, PlaylistError> {
+        let name = clean_name(name)?;
+        let id = self.next_id;
+        self.next_id += 1;
+        self.playlists.push(Playlist {
+            id,
+            name,
+            songs: Vec::new(),
+        });
+        self.save();
+        Ok(id)
+    }
+
+    pub fn rename(&mut self, id: u64, name: &str) -> Result<(), PlaylistError> {
+        let name = clean_name(name)?;
+        self.get_mut(id)?.name = name;
+        self.save();
+        Ok(())
+    }
+
+    pub fn delete(&mut self, id: u64) -> Result<(), PlaylistError> {
+        let index = self
+            .playlists
+            .iter()
+            .position(|playlist| playlist.id == id)
+            .ok_or(PlaylistError::NotFound)?;
+        self.playlists.remove(index);
+        if self.fallback == Some(id) {
+            self.fallback = None;
+        }
+        self.save();
+        Ok(())
+    }
+
+    pub fn add(&mut self, id: u64, song: SavedSong) -> Result<(), PlaylistError> {
+        self.get_mut(id)?.songs.push(song);
+        self.save();
+        Ok(())
+    }
+
+    pub fn remove(&mut self, id: u64, index: usize) -> Result<(), PlaylistError> {
+        let songs = &mut self.get_mut(id)?.songs;
+        if index >= songs.len() {
+            return Err(PlaylistError::InvalidIndex);
+        }
+        songs.remove(index);
+        self.save();
+        Ok(())
+    }
+
+    /// `None` stops playing a fallback playlist
+    pub fn set_fallback(&mut self, id: Option<u64>) -> Result<(), PlaylistError> {
+        if let Some(id) = id {
+            self.get_mut(id)?;
+        }
+        if self.fallback != id {
+            self.fallback = id;
+            self.fallback_next = 0;
+            self.save();
+        }
+        Ok(())
+    }
+
+    /// Next song of the fallback playlist, it starts over once every song was played
+    fn next_fallback(&mut self) -> Option<SavedSong> {
+        let songs = &self.get(self.fallback?)?.songs;
+        if songs.is_empty() {
+            return None;
+        }
+        let song = songs[self.fallback_next % songs.len()].clone();
+        self.fallback_next = (self.fallback_next + 1) % songs.len();
+        self.save();
+        Some(song)
+    }
+
+    pub fn list(&self) -> &[Playlist] {
+        &self.playlists
+    }
+}
+
+fn clean_name(name: &str) -> Result<String, PlaylistError> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(PlaylistError::InvalidName);
+    }
+    Ok(name.chars().take(MAX_NAME_LEN).collect())
+}
+
+/// Put `songs` at the end of the queue, returns how many were added
+///
+/// Links are fetched again like new requests, local files that are gone are left out
+pub async fn enqueue_songs(
+    state: &Mutex<AppState<'_>>,
+    config: &Config,
+    songs: Vec<SavedSong>,
+    requester: &Requester,
+) -> usize {
+    let mut added = 0;
+    for song in songs {
+        match song {
+            SavedSong::Link { url, .. } => {
+                enqueue_link(state, config, url, requester.clone()).await;
+                added += 1;
+            }
+            SavedSong::Local(track) => {
+                // Library ids change between scans, the path doesn't
+                let mut state = state.lock().await;
+                match state.library.find_path(&track.path).cloned() {
+                    Some(track) => {
+                        state.queue.push_local(track, requester.clone());
+                        added += 1;
+                    }
+                    None => warn!(
+                        "Local track is not in the library: {}",
+                        track.path.display()
+                    ),
+                }
+            }
+        }
+    }
+    added
+}
+
+/// Enqueue songs of the fallback playlist one by one whenever nothing is playing or queued
+///
+/// A fallback link that fails to fetch is dropped from the queue, and the wait before the next
+/// song doubles until something plays.
+pub async fn fallback(
+    state: &Mutex<AppState<'_>>,
+    config: &Config,
+    handler_event_tx: Sender<HandlerEvent>,
+) {
+    let mut next_at = Instant::now();
+    let mut last_link = None::<String>;
+    let mut failures = 0;
+    loop {
+        Timer::after(FALLBACK_INTERVAL).await;
+        let (song, dropped) = {
+            let mut state = state.lock().await;
+            if state.now_playing.is_some() {
+                // Whatever plays, the fallback starts right away once it ends
+                failures = 0;
+                next_at = Instant::now();
+                last_link = None;
+                continue;
+            }
+            // Failed entries stay until dismissed, they don't count
+            let queued = state
+                .queue
+                .iter()
+                .any(|item| !matches!(item.entry, QueueEntry::Failed(_)));
+            if queued {
+                continue;
+            }
+            let failed = last_link.take().and_then(|url| {
+                state.queue.iter().find_map(|item| match &item.entry {
+                    QueueEntry::Failed(failed)
+                        if failed.url() == url && item.requester.user().is_none() =>
+                    {
+                        Some(item.id)
+                    }
+                    _ => None,
+                })
+            });
+            match failed {
+                Some(id) => {
+                    state.queue.dismiss(id);
+                    failures += 1;
+                    let delay = FALLBACK_INTERVAL
+                        .saturating_mul(2u32.saturating_pow(failures))
+                        .min(MAX_FALLBACK_DELAY);
+                    next_at = Instant::now() + delay;
+                    warn!("Fallback song failed, next one in {}s", delay.as_secs());
+                    (None, true)
+                }
+                None if Instant::now() < next_at => continue,
+                None => (state.playlists.next_fallback(), false),
+            }
+        };
+        if dropped {
+            let _ = handler_event_tx.send(HandlerEvent::UpdateQueue).await;
+        }
+        if let Some(song) = song {
+            info!("Queue is empty, playing from the fallback playlist");
+            if let SavedSong::Link { url, .. } = &song {
+                last_link = Some(url.clone());
+            }
+            if enqueue_songs(state, config, vec![song], &Requester::default()).await > 0 {
+                let _ = handler_event_tx.send(HandlerEvent::UpdateQueue).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,276 @@
+//! Queue updates sent to clients as patches against the last queue they got
+//!
+//! Each message has a sequence number. A client that misses one asks for the whole queue again
+//! with `queue_resync`.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::Duration,
+};
+
+use log::error;
+use serde_json::{Map, Value, json};
+use smol::{
+    Timer,
+    channel::{Receiver, Sender},
+    lock::Mutex,
+};
+
+use crate::{AppState, BroadcastEvent, config::Config, handler::queue_json};
+
+/// Changes within this delay go out as one message
+const COALESCE_DELAY: Duration = Duration::from_millis(250);
+
+/// The queue as last sent to clients
+#[derive(Debug, Default)]
+pub struct QueueSync {
+    seq: u64,
+    /// Content of the `queue` message
+    queue: Value,
+}
+
+impl QueueSync {
+    pub fn new(queue: Value) -> Self {
+        QueueSync { seq: 0, queue }
+    }
+
+    /// The whole queue, for a client that just connected or asked for a resync
+    pub fn snapshot(&self) -> Value {
+        let mut msg = self.queue.clone();
+        msg["msg"] = json!("queue");
+        msg["seq"] = json!(self.seq);
+        msg
+    }
+
+    /// Take the new content of the `queue` message, returns what to send to clients if it changed
+    ///
+    /// That is a `queue_patch` message, or the whole queue if the patch would be bigger.
+    pub fn update(&mut self, queue: Value) -> Option<Value> {
+        let old_entries = entries(&self.queue);
+        let new_entries = entries(&queue);
+        let ops = diff(old_entries, new_entries);
+
+        // Everything but the entries: now_playing, up_next, total_time, ...
+        let mut fields = Map::new();
+        if let Value::Object(queue) = &queue {
+            for (key, value) in queue {
+                if key != "queue" && self.queue.get(key) != Some(value) {
+                    fields.insert(key.clone(), value.clone());
+                }
+            }
+        }
+        if ops.is_empty() && fields.is_empty() {
+            return None;
+        }
+
+        self.seq += 1;
+        self.queue = queue;
+        let patch = json!({
+            "msg": "queue_patch",
+            "seq": self.seq,
+            "ops": ops,
+            "fields": fields,
+        });
+        let snapshot = self.snapshot();
+        let len = |msg: &Value| serde_json::to_vec(msg).map_or(usize::MAX, |msg| msg.len());
+        if len(&snapshot) < len(&patch) {
+            Some(snapshot)
+        } else {
+            Some(patch)
+        }
+    }
+}
+
+fn entries(queue: &Value) -> &[Value] {
+    queue
+        .get("queue")
+        .and_then(Value::as_array)
+        .map_or(&[], Vec::as_slice)
+}
+
+fn entry_id(entry: &Value) -> Option<u64> {
+    entry.get("id").and_then(Value::as_u64)
+}
+
+/// Operations turning `old` into `new`, to be applied in order:
+/// `remove` by id, `insert` at an index, `move` an id to an index, and `update` replacing an entry
+fn diff(old: &[Value], new: &[Value]) -> Vec<Value> {
+    let new_ids = new.iter().map(entry_id).collect::<HashSet<_>>();
+    let mut ops = Vec::new();
+    let mut kept = Vec::new();
+    let mut old_by_id = HashMap::new();
+    for entry in old {
+        let id = entry_id(entry);
+        if new_ids.contains(&id) {
+            kept.push(id);
+            old_by_id.insert(id, entry);
+        } else {
+            ops.push(json!({"op": "remove", "id": id}));
+        }
+    }
+
+    // After each step the client has `new[..index]`, then the kept entries not placed yet
+    let mut placed = HashSet::new();
+    let mut next_kept = kept.iter().peekable();
+    for (index, entry) in new.iter().enumerate() {
+        while next_kept.next_if(|id| placed.contains(*id)).is_some() {}
+        let id = entry_id(entry);
+        match old_by_id.get(&id) {
+            Some(old) => {
+                if next_kept.next_if_eq(&&id).is_none() {
+                    ops.push(json!({"op": "move", "id": id, "index": index}));
+                }
+                if *old != entry {
+                    ops.push(json!({"op": "update", "entry": entry}));
+                }
+            }
+            None => ops.push(json!({"op": "insert", "index": index, "entry": entry})),
+        }
+        placed.insert(id);
+    }
+    ops
+}
+
+/// Send queue changes to every client, bursts of changes are sent together
+pub async fn broadcast_queue(
+    state: &Mutex<AppState<'_>>,
+    config: &Config,
+    update_rx: Receiver<()>,
+    broadcast_tx: Sender<BroadcastEvent>,
+) {
+    while update_rx.recv().await.is_ok() {
+        Timer::after(COALESCE_DELAY).await;
+        while update_rx.try_recv().is_ok() {}
+        let msg = {
+            let mut state = state.lock().await;
+            let queue = queue_json(&state, config);
+            state.queue_sync.update(queue)
+        };
+        let Some(msg) = msg else {
+            continue;
+        };
+        match serde_json::to_string(&msg) {
+            Ok(msg) => {
+                let _ = broadcast_tx
+                    .send(BroadcastEvent::Queue(Arc::from(msg)))
+                    .await;
+            }
+            Err(error) => error!("Failed to serialize queue update: {error}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What clients do with a `queue_patch` message
+    fn apply(old: &[Value], ops: &[Value]) -> Vec<Value> {
+        let mut entries = old.to_vec();
+        let position = |entries: &[Value], id: &Value| {
+            entries.iter().position(|entry| &entry["id"] == id).unwrap()
+        };
+        for op in ops {
+            match op["op"].as_str().unwrap() {
+                "remove" => {
+                    entries.remove(position(&entries, &op["id"]));
+                }
+                "insert" => {
+                    entries.insert(op["index"].as_u64().unwrap() as usize, op["entry"].clone())
+                }
+                "move" => {
+                    let entry = entries.remove(position(&entries, &op["id"]));
+                    entries.insert(op["index"].as_u64().unwrap() as usize, entry);
+                }
+                "update" => {
+                    let index = position(&entries, &op["entry"]["id"]);
+                    entries[index] = op["entry"].clone();
+                }
+                op => panic!("unknown op {op}"),
+            }
+        }
+        entries
+    }
+
+    fn queue(entries: &[(u64, &str)]) -> Vec<Value> {
+        entries
+            .iter()
+            .map(|(id, title)| json!({"id": id, "title": title}))
+            .collect()
+    }
+
+    fn assert_diff(old: &[Value], new: &[Value]) -> Vec<Value> {
+        let ops = diff(old, new);
+        assert_eq!(apply(old, &ops), new, "ops: {ops:?}");
+        ops
+    }
+
+    fn op_names(ops: &[Value]) -> Vec<&str> {
+        ops.iter().map(|op| op["op"].as_str().unwrap()).collect()
+    }
+
+    #[test]
+    fn unchanged_queue_has_no_ops() {
+        let old = queue(&[(1, "a"), (2, "b")]);
+        assert!(assert_diff(&old, &old).is_empty());
+    }
+
+    #[test]
+    fn remove_insert_and_update() {
+        let old = queue(&[(1, "a"), (2, "b"), (3, "c")]);
+        let new = queue(&[(2, "b"), (4, "d"), (3, "c fetched")]);
+        let ops = assert_diff(&old, &new);
+        assert_eq!(op_names(&ops), ["remove", "insert", "update"]);
+    }
+
+    #[test]
+    fn moves() {
+        // Played first: one entry moved forward, the others keep their place
+        let old = queue(&[(1, "a"), (2, "b"), (3, "c"), (4, "d")]);
+        let new = queue(&[(3, "c"), (1, "a"), (2, "b"), (4, "d")]);
+        let ops = assert_diff(&old, &new);
+        assert_eq!(op_names(&ops), ["move"]);
+
+        // Moved back, with the head policy `move_back`
+        let new = queue(&[(2, "b"), (3, "c"), (4, "d"), (1, "a")]);
+        assert_diff(&old, &new);
+
+        let new = queue(&[(4, "d"), (3, "c"), (2, "b"), (1, "a")]);
+        assert_diff(&old, &new);
+    }
+
+    #[test]
+    fn everything_at_once() {
+        let old = queue(&[(1, "a"), (2, "b"), (3, "c"), (4, "d"), (5, "e")]);
+        let new = queue(&[(5, "e"), (6, "f"), (3, "c2"), (1, "a"), (7, "g")]);
+        let ops = assert_diff(&old, &new);
+        for name in ["remove", "insert", "move", "update"] {
+            assert!(op_names(&ops).contains(&name), "{name} missing: {ops:?}");
+        }
+        assert_diff(&[], &new);
+        assert_diff(&new, &[]);
+    }
+
+    #[test]
+    fn shorter_message_is_sent() {
+        let mut sync = QueueSync::new(json!({"queue": queue(&[(1, "a"), (2, "b")]), "up_next": 0}));
+        let msg = sync
+            .update(json!({"queue": queue(&[(1, "a"), (2, "b")]), "up_next": 1}))
+            .unwrap();
+        assert_eq!(msg["msg"], "queue_patch");
+        assert_eq!(msg["seq"], 1);
+        assert_eq!(msg["fields"], json!({"up_next": 1}));
+
+        // Replacing every entry is shorter as a whole queue
+        let msg = sync
+            .update(json!({"queue": queue(&[(3, "c"), (4, "d")]), "up_next": 1}))
+            .unwrap();
+        assert_eq!(msg["msg"], "queue");
+        assert_eq!(msg["seq"], 2);
+        assert_eq!(sync.snapshot(), msg);
+
+        let same = json!({"queue": queue(&[(3, "c"), (4, "d")]), "up_next": 1});
+        assert!(sync.update(same).is_none());
+    }
+}
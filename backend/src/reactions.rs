@@ -0,0 +1,45 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    net::IpAddr,
+};
+
+/// Reactions listeners can send for the song playing, the frontend shows them as emoji
+pub const REACTIONS: [&str; 6] = ["like", "dislike", "love", "fire", "laugh", "sleep"];
+
+/// Reactions to the song playing, at most one of each kind per client address
+///
+/// Counting by address rather than by user keeps a script making up tokens from inflating them.
+#[derive(Debug, Default)]
+pub struct Reactions {
+    by_addr: HashMap<IpAddr, BTreeSet<&'static str>>,
+}
+
+impl Reactions {
+    /// Add the reaction sent from `addr`, or take it back if it was already there
+    ///
+    /// `like` and `dislike` replace each other. Returns `false` for an unknown reaction.
+    pub fn toggle(&mut self, addr: IpAddr, reaction: &str) -> bool {
+        let Some(reaction) = REACTIONS.into_iter().find(|known| *known == reaction) else {
+            return false;
+        };
+        let reactions = self.by_addr.entry(addr).or_default();
+        if !reactions.remove(reaction) {
+            match reaction {
+                "like" => reactions.remove("dislike"),
+                "dislike" => reactions.remove("like"),
+                _ => false,
+            };
+            reactions.insert(reaction);
+        }
+        true
+    }
+
+    /// Number of addresses that sent each reaction, reactions nobody sent are left out
+    pub fn counts(&self) -> BTreeMap<String, u32> {
+        let mut counts = BTreeMap::new();
+        for reaction in self.by_addr.values().flatten() {
+            *counts.entry((*reaction).to_owned()).or_default() += 1;
+        }
+        counts
+    }
+}
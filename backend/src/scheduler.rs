@@ -0,0 +1,258 @@
+use std::time::Duration;
+
+use chrono::{Datelike, Local, NaiveDateTime, NaiveTime, Weekday};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use smol::{Timer, channel::Sender, lock::Mutex};
+
+use crate::{AppState, HandlerEvent, config::Config, handler::announce};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A `[[schedule]]` entry of the config
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduleRule {
+    pub name: String,
+    /// Days the rule starts on, every day if empty
+    #[serde(default)]
+    pub days: Vec<Days>,
+    pub from: NaiveTime,
+    /// Can be earlier than `from`, the rule then ends on the next day
+    ///
+    /// Without it the rule is never active, it only plays its announcement at `from`
+    pub to: Option<NaiveTime>,
+    /// Pause when the rule starts, resume when it ends
+    #[serde(default)]
+    pub pause: bool,
+    /// Volume can't go above this while the rule is active
+    pub max_volume: Option<f32>,
+    /// File in the announcement directory to play when the rule starts
+    pub announce: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Days {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+    /// Monday to Friday
+    Weekdays,
+    /// Saturday and Sunday
+    Weekend,
+}
+
+impl Days {
+    fn contains(self, weekday: Weekday) -> bool {
+        match self {
+            Days::Mon => weekday == Weekday::Mon,
+            Days::Tue => weekday == Weekday::Tue,
+            Days::Wed => weekday == Weekday::Wed,
+            Days::Thu => weekday == Weekday::Thu,
+            Days::Fri => weekday == Weekday::Fri,
+            Days::Sat => weekday == Weekday::Sat,
+            Days::Sun => weekday == Weekday::Sun,
+            Days::Weekdays => !matches!(weekday, Weekday::Sat | Weekday::Sun),
+            Days::Weekend => matches!(weekday, Weekday::Sat | Weekday::Sun),
+        }
+    }
+}
+
+fn starts_on(days: &[Days], weekday: Weekday) -> bool {
+    days.is_empty() || days.iter().any(|days| days.contains(weekday))
+}
+
+/// Whether `now` is between `from` and `to` of a day in `days`, `to` can be on the next day
+fn in_window(days: &[Days], from: NaiveTime, to: NaiveTime, now: NaiveDateTime) -> bool {
+    let (weekday, time) = (now.weekday(), now.time());
+    if from < to {
+        starts_on(days, weekday) && from <= time && time < to
+    } else {
+        // Wraps past midnight, `from == to` is a whole day
+        (starts_on(days, weekday) && time >= from) || (starts_on(days, weekday.pred()) && time < to)
+    }
+}
+
+/// Time of day on some days of the week, like a rule without actions
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TimeWindow {
+    /// Days the window starts on, every day if empty
+    #[serde(default)]
+    pub days: Vec<Days>,
+    pub from: NaiveTime,
+    /// Can be earlier than `from`, the window then ends on the next day
+    pub to: NaiveTime,
+}
+
+impl TimeWindow {
+    pub fn is_active(&self, now: NaiveDateTime) -> bool {
+        in_window(&self.days, self.from, self.to, now)
+    }
+}
+
+impl ScheduleRule {
+    fn is_active(&self, now: NaiveDateTime) -> bool {
+        self.to
+            .is_some_and(|to| in_window(&self.days, self.from, to, now))
+    }
+
+    /// Whether the rule started in `(last, now]`
+    fn started_between(&self, last: NaiveDateTime, now: NaiveDateTime) -> bool {
+        // The previous day too, in case midnight was crossed since the last check
+        [Some(now.date()), now.date().pred_opt()]
+            .into_iter()
+            .flatten()
+            .map(|date| date.and_time(self.from))
+            .any(|start| starts_on(&self.days, start.weekday()) && last < start && start <= now)
+    }
+}
+
+/// Index of the rule in effect, the first one listed wins when several are active
+fn active_rule(rules: &[ScheduleRule], now: NaiveDateTime) -> Option<usize> {
+    rules.iter().position(|rule| rule.is_active(now))
+}
+
+/// Apply the `[[schedule]]` rules of the config as time goes by
+pub async fn scheduler(
+    state: &Mutex<AppState<'_>>,
+    config: &Config,
+    handler_event_tx: Sender<HandlerEvent>,
+) {
+    let rules = &config.schedule;
+    if rules.is_empty() {
+        return;
+    }
+    let mut current = None;
+    let mut last = Local::now().naive_local();
+    loop {
+        let now = Local::now().naive_local();
+        for rule in rules {
+            let Some(file) = &rule.announce else {
+                continue;
+            };
+            if rule.started_between(last, now) {
+                info!("Schedule rule {:?} announces {file:?}", rule.name);
+                if !announce(config, &handler_event_tx, file).await {
+                    warn!(
+                        "Announcement {file:?} of schedule rule {:?} not found",
+                        rule.name
+                    );
+                }
+            }
+        }
+        last = now;
+
+        let active = active_rule(rules, now);
+        if active != current {
+            let old = current.map(|index| &rules[index]);
+            let new = active.map(|index| &rules[index]);
+            match new {
+                Some(rule) => info!("Schedule rule {:?} starts", rule.name),
+                None => info!("Schedule rule {:?} ends", old.map_or("", |rule| &rule.name)),
+            }
+            state.lock().await.player.schedule = new.cloned();
+
+            let was_paused = old.is_some_and(|rule| rule.pause);
+            let pause = new.is_some_and(|rule| rule.pause);
+            if pause && !was_paused {
+                let _ = handler_event_tx.send(HandlerEvent::Pause).await;
+            } else if was_paused && !pause {
+                let _ = handler_event_tx.send(HandlerEvent::Resume).await;
+            }
+            // Apply the new cap, or the requested volume again once a capping rule ends,
+            // clients get the new rule with the volume
+            let _ = handler_event_tx.send(HandlerEvent::SetVolume).await;
+            current = active;
+        }
+        Timer::after(CHECK_INTERVAL).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::*;
+
+    fn time(hour: u32, minute: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    /// 2026-10-19 is a Monday
+    fn at(day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2026, 10, day)
+            .unwrap()
+            .and_time(time(hour, minute))
+    }
+
+    #[test]
+    fn window_within_a_day() {
+        let days = [Days::Mon];
+        let (from, to) = (time(14, 0), time(15, 30));
+        assert!(!in_window(&days, from, to, at(19, 13, 59)));
+        assert!(in_window(&days, from, to, at(19, 14, 0)));
+        assert!(in_window(&days, from, to, at(19, 15, 29)));
+        assert!(!in_window(&days, from, to, at(19, 15, 30)));
+        // Tuesday
+        assert!(!in_window(&days, from, to, at(20, 14, 30)));
+    }
+
+    #[test]
+    fn window_past_midnight_belongs_to_the_day_it_starts() {
+        let days = [Days::Fri];
+        let (from, to) = (time(22, 0), time(2, 0));
+        // Friday night and Saturday early morning
+        assert!(in_window(&days, from, to, at(23, 23, 0)));
+        assert!(in_window(&days, from, to, at(24, 1, 59)));
+        assert!(!in_window(&days, from, to, at(24, 2, 0)));
+        // Friday early morning belongs to Thursday night
+        assert!(!in_window(&days, from, to, at(23, 1, 0)));
+        // Saturday night
+        assert!(!in_window(&days, from, to, at(24, 23, 0)));
+    }
+
+    #[test]
+    fn window_with_from_equal_to_lasts_a_whole_day() {
+        let days = [Days::Weekend];
+        let (from, to) = (time(6, 0), time(6, 0));
+        // Saturday 06:00 to Monday 06:00
+        assert!(!in_window(&days, from, to, at(24, 5, 59)));
+        assert!(in_window(&days, from, to, at(24, 6, 0)));
+        assert!(in_window(&days, from, to, at(25, 12, 0)));
+        assert!(in_window(&days, from, to, at(26, 5, 59)));
+        assert!(!in_window(&days, from, to, at(26, 6, 0)));
+    }
+
+    #[test]
+    fn days() {
+        assert!(starts_on(&[], Weekday::Sun));
+        assert!(starts_on(&[Days::Weekdays], Weekday::Fri));
+        assert!(!starts_on(&[Days::Weekdays], Weekday::Sat));
+        assert!(starts_on(&[Days::Mon, Days::Weekend], Weekday::Sun));
+        assert!(!starts_on(&[Days::Mon, Days::Weekend], Weekday::Tue));
+    }
+
+    #[test]
+    fn rule_start_across_midnight() {
+        let rule = ScheduleRule {
+            name: "Closing".to_owned(),
+            days: vec![Days::Sun],
+            from: time(23, 59),
+            to: None,
+            pause: false,
+            max_volume: None,
+            announce: None,
+        };
+        // Sunday 23:58 to Monday 00:01
+        assert!(rule.started_between(at(25, 23, 58), at(26, 0, 1)));
+        assert!(!rule.started_between(at(25, 23, 59), at(26, 0, 1)));
+        // Without `to` the rule only announces
+        assert!(!rule.is_active(at(25, 23, 59)));
+    }
+}
@@ -0,0 +1,39 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    audio_output::AudioOutput,
+    store::{PendingWrite, StoreFile},
+};
+
+const SETTINGS_FILE: &str = "settings.json";
+
+/// Choices made at runtime by the admin, saved to `settings.json` in the data directory
+///
+/// Unset values fall back to the config file
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub audio_output: Option<AudioOutput>,
+    #[serde(skip)]
+    file: StoreFile,
+}
+
+impl Settings {
+    pub fn load(data_dir: &Path) -> Settings {
+        let (settings, file) = StoreFile::load(data_dir, SETTINGS_FILE, "settings");
+        Settings { file, ..settings }
+    }
+
+    /// Written by [`store::save_files`](crate::store::save_files), not while the state is locked
+    pub fn save(&mut self) {
+        self.file.mark_changed();
+    }
+
+    /// Content of `settings.json` if it changed since the last call
+    pub fn take_changes(&mut self) -> Option<PendingWrite> {
+        let path = self.file.take_changed()?;
+        Some(PendingWrite::new(path, self))
+    }
+}
@@ -1,103 +1,216 @@
-use std::{collections::VecDeque, mem::take, time::Duration};
+use std::{
+    collections::VecDeque,
+    fs,
+    io::ErrorKind,
+    mem::take,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use futures::StreamExt;
-use log::error;
-use smol::{Executor, Task, Timer, channel::Sender, lock::Mutex};
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use smol::{
+    Executor, Task, Timer,
+    channel::Sender,
+    lock::{Mutex, Semaphore},
+};
 
 use crate::{
-    AppState, HandlerEvent,
-    yt_dlp::{YoutubeInfo, YtdlpResult, get_ytdlp},
+    AppState, BroadcastEvent, HandlerEvent,
+    config::{Config, HeadPolicy, QueueConfig},
+    health::HEALTH,
+    library::{Library, LocalTrack},
+    metrics::{self, METRICS},
+    yt_dlp::{ErrorAction, YoutubeInfo, YtdlpError, YtdlpResult, get_ytdlp},
 };
 
-#[derive(Debug, Default)]
+const QUEUE_FILE: &str = "queue.json";
+/// yt-dlp processes running at once, a big import waits for free slots
+const MAX_FETCHES: usize = 5;
+
+#[derive(Debug)]
 pub struct SongQueue<'ex> {
-    queue: VecDeque<QueueEntry>,
+    queue: VecDeque<QueueItem>,
     executor: Executor<'ex>,
+    /// One permit per running yt-dlp process, see [`MAX_FETCHES`]
+    fetches: Arc<Semaphore>,
+    next_id: u64,
+}
+
+#[derive(Debug)]
+pub struct QueueItem {
+    pub id: u64,
+    pub requester: Requester,
+    pub entry: QueueEntry,
+}
+
+/// The client that put an entry in the queue
+#[derive(Debug, Clone, Default)]
+pub struct Requester {
+    notify: Option<Sender<BroadcastEvent>>,
+    /// Id in [`Users`](crate::users::Users), once the client said hello
+    user: Option<u64>,
+}
+
+#[derive(Debug, Clone)]
+pub enum Song {
+    Youtube(YoutubeInfo),
+    Local(LocalTrack),
 }
 
 #[derive(Debug)]
 pub enum QueueEntry {
     Fetched(YoutubeInfo),
+    Local(LocalTrack),
     Fetching(FetchTask),
     Refetching(RefetchTask),
     PendingRefetch(PendingRefetchTask),
+    RetryPending(RetryTask),
+    Failed(FailedTask),
 }
 
 #[derive(Debug)]
 pub struct FetchTask {
     url: String,
-    task: Task<anyhow::Result<YtdlpResult>>,
+    attempt: u32,
+    task: Task<Result<YtdlpResult, YtdlpError>>,
 }
 
 #[derive(Debug)]
 pub struct RefetchTask {
     url: String,
     title: String,
-    task: Task<anyhow::Result<YtdlpResult>>,
+    source: Option<String>,
+    attempt: u32,
+    task: Task<Result<YtdlpResult, YtdlpError>>,
 }
 
 #[derive(Debug)]
 pub struct PendingRefetchTask {
     url: String,
     title: String,
+    source: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct FailedTask {
+    url: String,
+    /// Only known for playlist entries, a request without title may still turn out to be a playlist
+    title: Option<String>,
+    source: Option<String>,
+    /// Number of fetch attempts so far
+    attempt: u32,
+    error: String,
+}
+
+#[derive(Debug)]
+pub struct RetryTask {
+    failed: FailedTask,
+    retry_at: Instant,
+}
+
+/// A song saved to `queue.json` on shutdown, and put back in the queue on startup
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SavedSong {
+    /// Fetched again on restore, stream URLs expire
+    Link {
+        url: String,
+        title: Option<String>,
+        source: Option<String>,
+        /// Only known once fetched, for exports
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        duration: Option<u32>,
+    },
+    Local(LocalTrack),
 }
 
 pub async fn process_queue(
     state: &Mutex<AppState<'_>>,
+    config: &Config,
     handler_event_tx: Sender<HandlerEvent>,
 ) -> ! {
     const PERIOD: Duration = Duration::from_millis(100);
+    let timeout = config.ytdlp.timeout();
     let mut timer = Timer::interval(PERIOD);
     loop {
+        HEALTH.queue.tick();
         let mut queue_changed = false;
         {
             let mut state = state.lock().await;
-            if state.queue.executor.try_tick() {
+            let now = Instant::now();
+            if state.queue.executor.try_tick()
+                || state.queue.has_due_retry(now)
+                || state.queue.has_pending_refetch()
+            {
                 let old_queue = take(&mut state.queue.queue);
                 let mut fetching_counter = 0;
-                for entry in old_queue {
+                for QueueItem {
+                    id,
+                    requester,
+                    entry,
+                } in old_queue
+                {
                     match entry {
-                        QueueEntry::Fetched(info) => {
-                            state.queue.queue.push_back(QueueEntry::Fetched(info));
+                        QueueEntry::Fetched(_) | QueueEntry::Local(_) | QueueEntry::Failed(_) => {
+                            state.queue.push_back(id, requester, entry);
                         }
                         QueueEntry::Fetching(task) => {
                             if task.task.is_finished() {
                                 queue_changed = true;
                                 match task.task.await {
                                     Ok(YtdlpResult::Single(info)) => {
-                                        state.queue.queue.push_back(QueueEntry::Fetched(info));
+                                        state.queue.push_back(
+                                            id,
+                                            requester,
+                                            QueueEntry::Fetched(*info),
+                                        );
                                     }
                                     Ok(YtdlpResult::Playlist(list)) => {
                                         for info in list {
-                                            let url = format!(
-                                                "https://www.youtube.com/watch?v={}",
-                                                info.id
-                                            );
-                                            let title = info.title;
-                                            if fetching_counter < 5 {
-                                                let future = get_ytdlp(url.clone());
-                                                let task = state.queue.executor.spawn(future);
-                                                let task = RefetchTask { url, title, task };
-                                                state
-                                                    .queue
-                                                    .queue
-                                                    .push_back(QueueEntry::Refetching(task));
+                                            let url = info.url;
+                                            let title = info.title.unwrap_or_else(|| url.clone());
+                                            let source = info.ie_key;
+                                            let entry = if fetching_counter < MAX_FETCHES {
+                                                let future = get_ytdlp(url.clone(), timeout);
+                                                let task = state.queue.spawn_fetch(future);
                                                 fetching_counter += 1;
+                                                QueueEntry::Refetching(RefetchTask {
+                                                    url,
+                                                    title,
+                                                    source,
+                                                    attempt: 1,
+                                                    task,
+                                                })
                                             } else {
-                                                let task = PendingRefetchTask { url, title };
-                                                state
-                                                    .queue
-                                                    .queue
-                                                    .push_back(QueueEntry::PendingRefetch(task));
-                                            }
+                                                QueueEntry::PendingRefetch(PendingRefetchTask {
+                                                    url,
+                                                    title,
+                                                    source,
+                                                })
+                                            };
+                                            state.queue.push_new(requester.clone(), entry);
                                         }
                                     }
                                     Err(error) => {
-                                        error!("yt-dlp Failed: {error}");
+                                        let failed = FailedTask {
+                                            url: task.url,
+                                            title: None,
+                                            source: None,
+                                            attempt: task.attempt,
+                                            error: error.user_message(),
+                                        };
+                                        let entry =
+                                            fetch_failed(&config.queue, &requester, failed, &error);
+                                        state.queue.push_back(id, requester, entry);
                                     }
                                 };
                             } else {
-                                state.queue.queue.push_back(QueueEntry::Fetching(task));
+                                state
+                                    .queue
+                                    .push_back(id, requester, QueueEntry::Fetching(task));
                                 fetching_counter += 1;
                             }
                         }
@@ -106,35 +219,86 @@ pub async fn process_queue(
                                 queue_changed = true;
                                 match task.task.await {
                                     Ok(YtdlpResult::Single(info)) => {
-                                        state.queue.queue.push_back(QueueEntry::Fetched(info));
+                                        state.queue.push_back(
+                                            id,
+                                            requester,
+                                            QueueEntry::Fetched(*info),
+                                        );
                                     }
                                     Err(error) => {
-                                        error!("yt-dlp Failed: {error}");
+                                        let failed = FailedTask {
+                                            url: task.url,
+                                            title: Some(task.title),
+                                            source: task.source,
+                                            attempt: task.attempt,
+                                            error: error.user_message(),
+                                        };
+                                        let entry =
+                                            fetch_failed(&config.queue, &requester, failed, &error);
+                                        state.queue.push_back(id, requester, entry);
                                     }
                                     Ok(YtdlpResult::Playlist(_)) => {
                                         unreachable!();
                                     }
                                 };
                             } else {
-                                state.queue.queue.push_back(QueueEntry::Refetching(task));
+                                state
+                                    .queue
+                                    .push_back(id, requester, QueueEntry::Refetching(task));
                                 fetching_counter += 1;
                             }
                         }
                         QueueEntry::PendingRefetch(task) => {
-                            if fetching_counter < 5 {
-                                let future = get_ytdlp(task.url.clone());
+                            if fetching_counter < MAX_FETCHES {
+                                let future = get_ytdlp(task.url.clone(), timeout);
                                 let task = RefetchTask {
                                     url: task.url,
                                     title: task.title,
-                                    task: state.queue.executor.spawn(future),
+                                    source: task.source,
+                                    attempt: 1,
+                                    task: state.queue.spawn_fetch(future),
                                 };
-                                state.queue.queue.push_back(QueueEntry::Refetching(task));
-                                fetching_counter += 1;
-                            } else {
                                 state
                                     .queue
-                                    .queue
-                                    .push_back(QueueEntry::PendingRefetch(task));
+                                    .push_back(id, requester, QueueEntry::Refetching(task));
+                                fetching_counter += 1;
+                            } else {
+                                state.queue.push_back(
+                                    id,
+                                    requester,
+                                    QueueEntry::PendingRefetch(task),
+                                );
+                            }
+                        }
+                        QueueEntry::RetryPending(task) => {
+                            if task.retry_at <= now && fetching_counter < MAX_FETCHES {
+                                queue_changed = true;
+                                let failed = task.failed;
+                                let future = get_ytdlp(failed.url.clone(), timeout);
+                                let task = state.queue.spawn_fetch(future);
+                                let attempt = failed.attempt + 1;
+                                let entry = match failed.title {
+                                    Some(title) => QueueEntry::Refetching(RefetchTask {
+                                        url: failed.url,
+                                        title,
+                                        source: failed.source,
+                                        attempt,
+                                        task,
+                                    }),
+                                    None => QueueEntry::Fetching(FetchTask {
+                                        url: failed.url,
+                                        attempt,
+                                        task,
+                                    }),
+                                };
+                                state.queue.push_back(id, requester, entry);
+                                fetching_counter += 1;
+                            } else {
+                                state.queue.push_back(
+                                    id,
+                                    requester,
+                                    QueueEntry::RetryPending(task),
+                                );
                             }
                         }
                     }
@@ -148,43 +312,447 @@ pub async fn process_queue(
     }
 }
 
+/// Schedule a retry for transient errors, otherwise mark the entry as failed
+fn fetch_failed(
+    config: &QueueConfig,
+    requester: &Requester,
+    failed: FailedTask,
+    error: &YtdlpError,
+) -> QueueEntry {
+    if error.action() == ErrorAction::Retry && failed.attempt < config.max_attempts {
+        let delay = config.retry_delay(failed.attempt);
+        warn!(
+            "yt-dlp failed (attempt {}), retrying in {}s: {error}",
+            failed.attempt,
+            delay.as_secs()
+        );
+        QueueEntry::RetryPending(RetryTask {
+            failed,
+            retry_at: Instant::now() + delay,
+        })
+    } else {
+        report_error(requester, failed.name(), error);
+        metrics::inc(&METRICS.songs_failed);
+        QueueEntry::Failed(failed)
+    }
+}
+
+fn report_error(requester: &Requester, name: &str, error: &YtdlpError) {
+    match error.action() {
+        ErrorAction::Alert => {
+            error!("yt-dlp failed, the admin should take a look: {error}");
+        }
+        ErrorAction::Retry | ErrorAction::Report => {
+            warn!("yt-dlp failed on {name}: {error}");
+        }
+    }
+    requester.notify(format!("{}: {name}", error.user_message()));
+}
+
+impl Default for SongQueue<'_> {
+    fn default() -> Self {
+        SongQueue {
+            queue: VecDeque::new(),
+            executor: Executor::new(),
+            fetches: Arc::new(Semaphore::new(MAX_FETCHES)),
+            next_id: 0,
+        }
+    }
+}
+
 impl<'ex> SongQueue<'ex> {
+    /// Run a fetch once a yt-dlp slot is free, the entry shows as fetching meanwhile
+    fn spawn_fetch(
+        &self,
+        future: impl Future<Output = Result<YtdlpResult, YtdlpError>> + Send + 'ex,
+    ) -> Task<Result<YtdlpResult, YtdlpError>> {
+        let fetches = self.fetches.clone();
+        self.executor.spawn(async move {
+            let _slot = fetches.acquire_arc().await;
+            future.await
+        })
+    }
+
     pub fn push_task(
         &mut self,
-        future: impl Future<Output = anyhow::Result<YtdlpResult>> + Send + 'ex,
+        future: impl Future<Output = Result<YtdlpResult, YtdlpError>> + Send + 'ex,
         url: String,
+        requester: Requester,
     ) {
-        let task = self.executor.spawn(future);
-        let task = FetchTask { task, url };
-        self.queue.push_back(QueueEntry::Fetching(task));
+        metrics::inc(&METRICS.songs_requested);
+        let task = self.spawn_fetch(future);
+        let task = FetchTask {
+            task,
+            url,
+            attempt: 1,
+        };
+        self.push_new(requester, QueueEntry::Fetching(task));
+    }
+
+    pub fn push_local(&mut self, track: LocalTrack, requester: Requester) {
+        metrics::inc(&METRICS.songs_requested);
+        self.push_new(requester, QueueEntry::Local(track));
+    }
+
+    /// Update restored local tracks from a new library scan, matched by path
+    pub fn refresh_local(&mut self, library: &Library) {
+        for item in &mut self.queue {
+            if let QueueEntry::Local(track) = &mut item.entry
+                && let Some(scanned) = library.find_path(&track.path)
+            {
+                *track = scanned.clone();
+            }
+        }
+    }
+
+    fn push_new(&mut self, requester: Requester, entry: QueueEntry) {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.push_back(id, requester, entry);
     }
 
-    pub async fn try_pop(&mut self) -> Option<Option<YoutubeInfo>> {
-        let Some(first) = self.queue.pop_front() else {
+    fn push_back(&mut self, id: u64, requester: Requester, entry: QueueEntry) {
+        self.queue.push_back(QueueItem {
+            id,
+            requester,
+            entry,
+        });
+    }
+
+    /// `Some(None)` when nothing is left to play, `None` while waiting for a fetch
+    pub async fn try_pop(&mut self, policy: HeadPolicy) -> Option<Option<(Song, Requester)>> {
+        // Failed entries stay until dismissed, but never play
+        if self
+            .queue
+            .iter()
+            .all(|item| matches!(item.entry, QueueEntry::Failed(_)))
+        {
             return Some(None);
+        }
+        let index = self.next_ready_index(policy)?;
+        if policy == HeadPolicy::MoveBack {
+            let moved = self.queue.drain(..index).collect::<Vec<_>>();
+            self.queue.extend(moved);
+        }
+        let index = self.next_ready_index(policy)?;
+        let ready = self.queue.remove(index)?;
+        Some(ready.entry.into_song().map(|song| (song, ready.requester)))
+    }
+
+    /// Index of the entry the player will pop next, if any is ready
+    ///
+    /// Failed entries never block the queue, whatever the policy
+    pub fn next_ready_index(&self, policy: HeadPolicy) -> Option<usize> {
+        let mut entries = self
+            .queue
+            .iter()
+            .enumerate()
+            .filter(|(_, item)| !matches!(item.entry, QueueEntry::Failed(_)));
+        match policy {
+            HeadPolicy::Wait => entries
+                .next()
+                .filter(|(_, item)| item.entry.is_ready())
+                .map(|(index, _)| index),
+            HeadPolicy::SkipAhead | HeadPolicy::MoveBack => entries
+                .find(|(_, item)| item.entry.is_ready())
+                .map(|(index, _)| index),
+        }
+    }
+
+    /// Indices of the entries in the order the player will take them, if no other fetch finishes
+    ///
+    /// With `skip_ahead` and `move_back`, ready entries go before the ones still fetching.
+    /// Failed entries are left out.
+    pub fn play_order(&self, policy: HeadPolicy) -> Vec<usize> {
+        let (ready, waiting): (Vec<_>, Vec<_>) = self
+            .queue
+            .iter()
+            .enumerate()
+            .filter(|(_, item)| !matches!(item.entry, QueueEntry::Failed(_)))
+            .partition(|(_, item)| policy == HeadPolicy::Wait || item.entry.is_ready());
+        ready
+            .into_iter()
+            .chain(waiting)
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    fn has_due_retry(&self, now: Instant) -> bool {
+        self.queue.iter().any(|item| match &item.entry {
+            QueueEntry::RetryPending(task) => task.retry_at <= now,
+            _ => false,
+        })
+    }
+
+    /// Restored songs are waiting for a refetch without any fetch running,
+    /// so nothing would tick the executor
+    fn has_pending_refetch(&self) -> bool {
+        self.queue
+            .iter()
+            .any(|item| matches!(item.entry, QueueEntry::PendingRefetch(_)))
+    }
+
+    /// Put saved songs at the end of the queue
+    ///
+    /// Songs with a known title wait for a refetch, others are fetched right away
+    pub fn restore(&mut self, songs: Vec<SavedSong>, timeout: Duration) {
+        for song in songs {
+            let entry = match song {
+                SavedSong::Link {
+                    url,
+                    title: Some(title),
+                    source,
+                    ..
+                } => QueueEntry::PendingRefetch(PendingRefetchTask { url, title, source }),
+                SavedSong::Link {
+                    url, title: None, ..
+                } => {
+                    let task = self.spawn_fetch(get_ytdlp(url.clone(), timeout));
+                    QueueEntry::Fetching(FetchTask {
+                        url,
+                        attempt: 1,
+                        task,
+                    })
+                }
+                SavedSong::Local(track) => {
+                    if !track.path.exists() {
+                        warn!("Saved local track is gone: {}", track.path.display());
+                        continue;
+                    }
+                    QueueEntry::Local(track)
+                }
+            };
+            self.push_new(Requester::default(), entry);
+        }
+    }
+
+    /// Songs that can be saved, failed entries are left out
+    pub fn saved(&self) -> impl Iterator<Item = SavedSong> {
+        self.queue.iter().filter_map(|item| {
+            let song = match &item.entry {
+                QueueEntry::Fetched(info) => SavedSong::Link {
+                    url: info.webpage_url.clone(),
+                    title: Some(info.title.clone()),
+                    source: Some(info.extractor_key.clone()),
+                    duration: info.duration,
+                },
+                QueueEntry::Local(track) => SavedSong::Local(track.clone()),
+                QueueEntry::Fetching(task) => SavedSong::Link {
+                    url: task.url.clone(),
+                    title: None,
+                    source: None,
+                    duration: None,
+                },
+                QueueEntry::Refetching(task) => SavedSong::Link {
+                    url: task.url.clone(),
+                    title: Some(task.title.clone()),
+                    source: task.source.clone(),
+                    duration: None,
+                },
+                QueueEntry::PendingRefetch(task) => SavedSong::Link {
+                    url: task.url.clone(),
+                    title: Some(task.title.clone()),
+                    source: task.source.clone(),
+                    duration: None,
+                },
+                QueueEntry::RetryPending(task) => SavedSong::Link {
+                    url: task.failed.url.clone(),
+                    title: task.failed.title.clone(),
+                    source: task.failed.source.clone(),
+                    duration: None,
+                },
+                QueueEntry::Failed(_) => return None,
+            };
+            Some(song)
+        })
+    }
+
+    /// Retry a failed entry right away
+    ///
+    /// Returns `false` if there is no failed entry with this id
+    pub fn retry(&mut self, id: u64) -> bool {
+        let Some(index) = self.queue.iter().position(|item| item.id == id) else {
+            return false;
         };
-        match first {
-            QueueEntry::Fetched(info) => Some(Some(info)),
-            QueueEntry::Fetching(task) => {
-                self.queue.push_front(QueueEntry::Fetching(task));
-                None
-            }
-            QueueEntry::Refetching(task) => {
-                self.queue.push_front(QueueEntry::Refetching(task));
-                None
-            }
-            QueueEntry::PendingRefetch(task) => {
-                self.queue.push_front(QueueEntry::PendingRefetch(task));
-                None
+        let QueueItem {
+            id,
+            requester,
+            entry,
+        } = self.queue.remove(index).expect("index in queue");
+        let (entry, retried) = match entry {
+            QueueEntry::Failed(mut failed)
+            | QueueEntry::RetryPending(RetryTask { mut failed, .. }) => {
+                failed.attempt = 0;
+                let task = RetryTask {
+                    failed,
+                    retry_at: Instant::now(),
+                };
+                (QueueEntry::RetryPending(task), true)
             }
-        }
+            entry => (entry, false),
+        };
+        self.queue.insert(
+            index,
+            QueueItem {
+                id,
+                requester,
+                entry,
+            },
+        );
+        retried
     }
 
-    pub fn iter(&self) -> impl Iterator<Item = &QueueEntry> {
+    /// Remove every entry requested by `user`, returns how many were removed
+    pub fn remove_by_user(&mut self, user: u64) -> usize {
+        let before = self.queue.len();
+        self.queue.retain(|item| item.requester.user != Some(user));
+        before - self.queue.len()
+    }
+
+    /// Remove a failed entry from the queue
+    ///
+    /// Returns `false` if there is no failed entry with this id
+    pub fn dismiss(&mut self, id: u64) -> bool {
+        let Some(index) = self.queue.iter().position(|item| {
+            item.id == id
+                && matches!(
+                    item.entry,
+                    QueueEntry::Failed(_) | QueueEntry::RetryPending(_)
+                )
+        }) else {
+            return false;
+        };
+        self.queue.remove(index);
+        true
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &QueueItem> {
         self.queue.iter()
     }
 }
 
+impl Song {
+    pub fn saved(&self) -> SavedSong {
+        match self {
+            Song::Youtube(info) => SavedSong::Link {
+                url: info.webpage_url.clone(),
+                title: Some(info.title.clone()),
+                source: Some(info.extractor_key.clone()),
+                duration: info.duration,
+            },
+            Song::Local(track) => SavedSong::Local(track.clone()),
+        }
+    }
+
+    pub fn title(&self) -> &str {
+        match self {
+            Song::Youtube(info) => &info.title,
+            Song::Local(track) => &track.title,
+        }
+    }
+
+    /// Page URL, or file path for local files
+    pub fn url(&self) -> String {
+        match self {
+            Song::Youtube(info) => info.webpage_url.clone(),
+            Song::Local(track) => track.path.display().to_string(),
+        }
+    }
+
+    /// Site the song comes from, `local` for local files
+    pub fn source(&self) -> &str {
+        match self {
+            Song::Youtube(info) => &info.extractor_key,
+            Song::Local(_) => "local",
+        }
+    }
+
+    /// Channel, or artist for local files
+    pub fn channel(&self) -> Option<&str> {
+        match self {
+            Song::Youtube(info) => info.channel.as_deref(),
+            Song::Local(track) => track.artist.as_deref(),
+        }
+    }
+
+    pub fn duration(&self) -> Option<u32> {
+        match self {
+            Song::Youtube(info) => info.duration,
+            Song::Local(track) => track.duration,
+        }
+    }
+}
+
+impl Requester {
+    pub fn new(notify: Sender<BroadcastEvent>) -> Self {
+        Requester {
+            notify: Some(notify),
+            user: None,
+        }
+    }
+
+    pub fn user(&self) -> Option<u64> {
+        self.user
+    }
+
+    pub fn set_user(&mut self, user: u64) {
+        self.user = Some(user);
+    }
+
+    /// Show a snackbar message to the requester, if it is still connected
+    pub fn notify(&self, text: String) {
+        if let Some(notify) = &self.notify {
+            let _ = notify.try_send(BroadcastEvent::Snackbar(text));
+        }
+    }
+}
+
+impl QueueEntry {
+    /// Every value [`QueueEntry::state`] can return
+    pub const STATES: [&str; 5] = ["fetched", "fetching", "pending", "retry_pending", "failed"];
+
+    /// State reported to clients
+    pub fn state(&self) -> &'static str {
+        match self {
+            QueueEntry::Fetched(_) | QueueEntry::Local(_) => "fetched",
+            QueueEntry::Fetching(_) | QueueEntry::Refetching(_) => "fetching",
+            QueueEntry::PendingRefetch(_) => "pending",
+            QueueEntry::RetryPending(_) => "retry_pending",
+            QueueEntry::Failed(_) => "failed",
+        }
+    }
+
+    /// Length of the song, only known once fetched
+    pub fn duration(&self) -> Option<u32> {
+        match self {
+            QueueEntry::Fetched(info) => info.duration,
+            QueueEntry::Local(track) => track.duration,
+            QueueEntry::Fetching(_)
+            | QueueEntry::Refetching(_)
+            | QueueEntry::PendingRefetch(_)
+            | QueueEntry::RetryPending(_)
+            | QueueEntry::Failed(_) => None,
+        }
+    }
+
+    /// Whether the entry can be played right now
+    pub fn is_ready(&self) -> bool {
+        matches!(self, QueueEntry::Fetched(_) | QueueEntry::Local(_))
+    }
+
+    fn into_song(self) -> Option<Song> {
+        match self {
+            QueueEntry::Fetched(info) => Some(Song::Youtube(info)),
+            QueueEntry::Local(track) => Some(Song::Local(track)),
+            QueueEntry::Fetching(_)
+            | QueueEntry::Refetching(_)
+            | QueueEntry::PendingRefetch(_)
+            | QueueEntry::RetryPending(_)
+            | QueueEntry::Failed(_) => None,
+        }
+    }
+}
+
 impl FetchTask {
     pub fn url(&self) -> &str {
         &self.url
@@ -199,6 +767,10 @@ impl RefetchTask {
     pub fn title(&self) -> &str {
         &self.title
     }
+
+    pub fn source(&self) -> Option<&str> {
+        self.source.as_deref()
+    }
 }
 
 impl PendingRefetchTask {
@@ -209,4 +781,157 @@ impl PendingRefetchTask {
     pub fn title(&self) -> &str {
         &self.title
     }
+
+    pub fn source(&self) -> Option<&str> {
+        self.source.as_deref()
+    }
+}
+
+impl FailedTask {
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn title(&self) -> Option<&str> {
+        self.title.as_deref()
+    }
+
+    pub fn source(&self) -> Option<&str> {
+        self.source.as_deref()
+    }
+
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    pub fn error(&self) -> &str {
+        &self.error
+    }
+
+    fn name(&self) -> &str {
+        self.title.as_deref().unwrap_or(&self.url)
+    }
+}
+
+impl RetryTask {
+    pub fn failed(&self) -> &FailedTask {
+        &self.failed
+    }
+
+    /// Time left before the next attempt
+    pub fn retry_in(&self) -> Duration {
+        self.retry_at.saturating_duration_since(Instant::now())
+    }
+}
+
+/// The song playing followed by the queue
+pub fn saved_songs(state: &AppState) -> Vec<SavedSong> {
+    state
+        .now_playing
+        .iter()
+        .map(|now_playing| now_playing.song.saved())
+        .chain(state.queue.saved())
+        .collect()
+}
+
+/// Save the interrupted song and the queue to `data_dir`
+pub fn save_queue(state: &AppState, data_dir: &Path) -> anyhow::Result<()> {
+    let songs = saved_songs(state);
+    let path = data_dir.join(QUEUE_FILE);
+    if songs.is_empty() {
+        return match fs::remove_file(&path) {
+            Err(error) if error.kind() != ErrorKind::NotFound => Err(error.into()),
+            _ => Ok(()),
+        };
+    }
+    fs::create_dir_all(data_dir)?;
+    fs::write(&path, serde_json::to_vec(&songs)?)?;
+    info!("Saved {} songs to {}", songs.len(), path.display());
+    Ok(())
+}
+
+/// Take the songs saved by [`save_queue`], the file is removed so they are only restored once
+pub fn take_saved_queue(data_dir: &Path) -> Vec<SavedSong> {
+    let path = data_dir.join(QUEUE_FILE);
+    let songs = match fs::read(&path) {
+        Ok(content) => match serde_json::from_slice(&content) {
+            Ok(songs) => songs,
+            Err(error) => {
+                error!("Malformed saved queue {}: {error}", path.display());
+                return Vec::new();
+            }
+        },
+        Err(error) if error.kind() == ErrorKind::NotFound => return Vec::new(),
+        Err(error) => {
+            error!("Failed to read saved queue {}: {error}", path.display());
+            return Vec::new();
+        }
+    };
+    if let Err(error) = fs::remove_file(&path) {
+        error!("Failed to remove saved queue {}: {error}", path.display());
+    }
+    songs
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    fn failed(url: &str) -> QueueEntry {
+        QueueEntry::Failed(FailedTask {
+            url: url.to_owned(),
+            title: None,
+            source: None,
+            attempt: 1,
+            error: "Video unavailable".to_owned(),
+        })
+    }
+
+    fn local(title: &str) -> QueueEntry {
+        QueueEntry::Local(LocalTrack {
+            id: 0,
+            path: PathBuf::from(format!("/music/{title}.mp3")),
+            title: title.to_owned(),
+            artist: None,
+            duration: Some(180),
+        })
+    }
+
+    #[test]
+    fn only_failed_entries_left_is_empty() {
+        for policy in [
+            HeadPolicy::Wait,
+            HeadPolicy::SkipAhead,
+            HeadPolicy::MoveBack,
+        ] {
+            let mut queue = SongQueue::default();
+            assert!(matches!(smol::block_on(queue.try_pop(policy)), Some(None)));
+
+            queue.push_new(Requester::default(), failed("https://example.com/a"));
+            assert!(matches!(smol::block_on(queue.try_pop(policy)), Some(None)));
+            // Still shown until dismissed
+            assert_eq!(queue.iter().count(), 1);
+        }
+    }
+
+    #[test]
+    fn failed_entries_are_passed_over() {
+        for policy in [
+            HeadPolicy::Wait,
+            HeadPolicy::SkipAhead,
+            HeadPolicy::MoveBack,
+        ] {
+            let mut queue = SongQueue::default();
+            queue.push_new(Requester::default(), failed("https://example.com/a"));
+            queue.push_new(Requester::default(), local("b"));
+            let popped = smol::block_on(queue.try_pop(policy));
+            assert!(
+                matches!(&popped, Some(Some((Song::Local(track), _))) if track.title == "b"),
+                "{policy:?}: {popped:?}"
+            );
+            assert!(matches!(smol::block_on(queue.try_pop(policy)), Some(None)));
+        }
+    }
 }
@@ -0,0 +1,466 @@
+//! Usage statistics computed from the play history, for the club's monthly report
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write as _,
+    path::Path,
+    process::ExitCode,
+};
+
+use chrono::{DateTime, Local, NaiveDate, Timelike};
+use serde::Serialize;
+
+use crate::{
+    config::Config,
+    history::{self, HistoryRecord},
+};
+
+const DEFAULT_LIMIT: usize = 10;
+
+/// Which records to look at, dates are inclusive and in local time
+#[derive(Debug, Clone, Copy)]
+pub struct StatsQuery {
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+    /// Number of entries in each top list
+    pub limit: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Csv,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Stats {
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+    pub total: Entry,
+    pub top_tracks: Vec<Entry>,
+    pub top_channels: Vec<Entry>,
+    pub top_requesters: Vec<Entry>,
+    /// Plays started in each hour of the day, 0 to 23
+    pub busiest_hours: Vec<Hour>,
+}
+
+/// Totals of a track, channel, requester or the whole range
+#[derive(Debug, Default, Serialize)]
+pub struct Entry {
+    pub name: String,
+    /// Page URL or file path, for tracks only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    pub plays: u32,
+    pub skips: u32,
+    pub skip_rate: f64,
+    /// Seconds actually played
+    pub play_time: u64,
+    pub reactions: BTreeMap<String, u32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Hour {
+    pub hour: u32,
+    pub plays: u32,
+}
+
+impl Default for StatsQuery {
+    fn default() -> Self {
+        StatsQuery {
+            from: None,
+            to: None,
+            limit: DEFAULT_LIMIT,
+        }
+    }
+}
+
+impl StatsQuery {
+    /// Parse `from`, `to` (`YYYY-MM-DD`) and `limit`, `None` values are left to their default
+    pub fn parse(
+        from: Option<&str>,
+        to: Option<&str>,
+        limit: Option<&str>,
+    ) -> Result<Self, String> {
+        let date = |date: Option<&str>| {
+            date.map(|date| {
+                NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                    .map_err(|error| format!("Invalid date {date:?}, expected YYYY-MM-DD: {error}"))
+            })
+            .transpose()
+        };
+        let limit = match limit {
+            Some(limit) => limit
+                .parse()
+                .map_err(|error| format!("Invalid limit {limit:?}: {error}"))?,
+            None => DEFAULT_LIMIT,
+        };
+        Ok(StatsQuery {
+            from: date(from)?,
+            to: date(to)?,
+            limit,
+        })
+    }
+
+    fn contains(&self, date: NaiveDate) -> bool {
+        self.from.is_none_or(|from| from <= date) && self.to.is_none_or(|to| date <= to)
+    }
+}
+
+impl Format {
+    pub fn parse(format: &str) -> Option<Format> {
+        match format {
+            "json" => Some(Format::Json),
+            "csv" => Some(Format::Csv),
+            _ => None,
+        }
+    }
+}
+
+impl Entry {
+    fn add(&mut self, record: &HistoryRecord) {
+        self.plays += 1;
+        self.skips += u32::from(record.skipped);
+        self.play_time += u64::from(record.played);
+        for (reaction, count) in &record.reactions {
+            *self.reactions.entry(reaction.clone()).or_default() += count;
+        }
+    }
+
+    fn finish(mut self) -> Self {
+        if self.plays > 0 {
+            self.skip_rate = f64::from(self.skips) / f64::from(self.plays);
+        }
+        self
+    }
+}
+
+/// Compute statistics over the records of the history file in `data_dir`
+pub fn compute(data_dir: &Path, query: StatsQuery) -> std::io::Result<Stats> {
+    let records = match history::read_records(&history::file(data_dir)) {
+        Ok(records) => records,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(error) => return Err(error),
+    };
+    Ok(compute_records(&records, query))
+}
+
+fn compute_records(records: &[HistoryRecord], query: StatsQuery) -> Stats {
+    let mut total = Entry {
+        name: "total".to_owned(),
+        ..Entry::default()
+    };
+    let mut tracks = HashMap::<&str, Entry>::new();
+    let mut channels = HashMap::<&str, Entry>::new();
+    let mut requesters = HashMap::<String, Entry>::new();
+    let mut hours = [0; 24];
+
+    for record in records {
+        let Some(started_at) = i64::try_from(record.started_at)
+            .ok()
+            .and_then(|secs| DateTime::from_timestamp(secs, 0))
+        else {
+            continue;
+        };
+        let started_at = started_at.with_timezone(&Local);
+        // Counted when it plays again after the restart
+        if record.interrupted || !query.contains(started_at.date_naive()) {
+            continue;
+        }
+
+        total.add(record);
+        hours[started_at.hour() as usize] += 1;
+
+        let track = tracks.entry(&record.url).or_default();
+        // The latest title wins
+        track.name.clone_from(&record.title);
+        track.url = Some(record.url.clone());
+        track.add(record);
+
+        if let Some(channel) = &record.channel {
+            let entry = channels.entry(channel).or_default();
+            entry.name.clone_from(channel);
+            entry.add(record);
+        }
+
+        // Grouped by user id, nicknames can change
+        let key = match (record.requester_id, &record.requester) {
+            (Some(id), _) => format!("#{id}"),
+            (None, Some(nickname)) => nickname.clone(),
+            (None, None) => continue,
+        };
+        let entry = requesters.entry(key.clone()).or_default();
+        entry.name = record.requester.clone().unwrap_or(key);
+        entry.add(record);
+    }
+
+    let top = |entries: Vec<Entry>| {
+        let mut entries = entries.into_iter().map(Entry::finish).collect::<Vec<_>>();
+        entries.sort_by(|a, b| {
+            b.plays
+                .cmp(&a.plays)
+                .then(b.play_time.cmp(&a.play_time))
+                .then_with(|| a.name.cmp(&b.name))
+        });
+        entries.truncate(query.limit);
+        entries
+    };
+
+    Stats {
+        from: query.from,
+        to: query.to,
+        total: total.finish(),
+        top_tracks: top(tracks.into_values().collect()),
+        top_channels: top(channels.into_values().collect()),
+        top_requesters: top(requesters.into_values().collect()),
+        busiest_hours: (0..24)
+            .map(|hour| Hour {
+                hour,
+                plays: hours[hour as usize],
+            })
+            .collect(),
+    }
+}
+
+impl Stats {
+    /// One table with a `section` column, so it opens in any spreadsheet
+    pub fn to_csv(&self) -> String {
+        let mut csv = "section,name,url,plays,skips,skip_rate,play_time,reactions\n".to_owned();
+        let sections = [
+            ("total", std::slice::from_ref(&self.total)),
+            ("track", self.top_tracks.as_slice()),
+            ("channel", self.top_channels.as_slice()),
+            ("requester", self.top_requesters.as_slice()),
+        ];
+        for (section, entries) in sections {
+            for entry in entries {
+                let reactions = entry
+                    .reactions
+                    .iter()
+                    .map(|(reaction, count)| format!("{reaction}:{count}"))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                let _ = writeln!(
+                    csv,
+                    "{section},{},{},{},{},{:.3},{},{}",
+                    csv_field(&entry.name),
+                    csv_field(entry.url.as_deref().unwrap_or_default()),
+                    entry.plays,
+                    entry.skips,
+                    entry.skip_rate,
+                    entry.play_time,
+                    csv_field(&reactions),
+                );
+            }
+        }
+        for hour in &self.busiest_hours {
+            let _ = writeln!(csv, "hour,{:02}:00,,{},,,,", hour.hour, hour.plays);
+        }
+        csv
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    // A title like `=HYPERLINK(...)` would run as a formula in a spreadsheet
+    let field = if field.starts_with(['=', '+', '-', '@', '\t', '\r']) {
+        format!("'{field}")
+    } else {
+        field.to_owned()
+    };
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field
+    }
+}
+
+const USAGE: &str = "Usage: club_cafe_music_backend stats [--from YYYY-MM-DD] [--to YYYY-MM-DD] \
+                     [--limit N] [--format json|csv]";
+
+/// `stats` subcommand, prints statistics of the history in the configured data directory
+pub fn cli(args: impl Iterator<Item = String>) -> ExitCode {
+    let mut from = None;
+    let mut to = None;
+    let mut limit = None;
+    let mut format = Format::Json;
+    let mut args = args;
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| format!("Missing value for {arg}"))
+        };
+        let result = match arg.as_str() {
+            "--from" => value().map(|value| from = Some(value)),
+            "--to" => value().map(|value| to = Some(value)),
+            "--limit" => value().map(|value| limit = Some(value)),
+            "--format" => value().and_then(|value| {
+                format = Format::parse(&value).ok_or(format!("Unknown format {value:?}"))?;
+                Ok(())
+            }),
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                return ExitCode::SUCCESS;
+            }
+            _ => Err(format!("Unknown argument {arg:?}")),
+        };
+        if let Err(error) = result {
+            eprintln!("{error}\n{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    }
+
+    let query = match StatsQuery::parse(from.as_deref(), to.as_deref(), limit.as_deref()) {
+        Ok(query) => query,
+        Err(error) => {
+            eprintln!("{error}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("Failed to load config: {error:#}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let stats = match compute(&config.data_dir, query) {
+        Ok(stats) => stats,
+        Err(error) => {
+            eprintln!("Failed to read history: {error}");
+            return ExitCode::FAILURE;
+        }
+    };
+    match format {
+        Format::Json => match serde_json::to_string_pretty(&stats) {
+            Ok(json) => println!("{json}"),
+            Err(error) => {
+                eprintln!("{error}");
+                return ExitCode::FAILURE;
+            }
+        },
+        Format::Csv => print!("{}", stats.to_csv()),
+    }
+    ExitCode::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn record(
+        date: (i32, u32, u32),
+        hour: u32,
+        url: &str,
+        requester_id: Option<u64>,
+    ) -> HistoryRecord {
+        let started_at = Local
+            .with_ymd_and_hms(date.0, date.1, date.2, hour, 30, 0)
+            .unwrap();
+        HistoryRecord {
+            started_at: started_at.timestamp() as u64,
+            title: format!("Title of {url}"),
+            url: url.to_owned(),
+            source: "yt".to_owned(),
+            channel: Some("Channel".to_owned()),
+            duration: Some(200),
+            played: 100,
+            skipped: false,
+            interrupted: false,
+            requester_id,
+            requester: requester_id.map(|id| format!("User {id}")),
+            reactions: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn totals_and_top_lists() {
+        let mut skipped = record((2026, 10, 2), 20, "b", Some(2));
+        skipped.skipped = true;
+        skipped.reactions.insert("like".to_owned(), 2);
+        let records = [
+            record((2026, 10, 1), 20, "a", Some(1)),
+            record((2026, 10, 1), 21, "a", Some(1)),
+            skipped,
+            record((2026, 10, 3), 20, "c", None),
+        ];
+        let stats = compute_records(&records, StatsQuery::default());
+
+        assert_eq!(stats.total.plays, 4);
+        assert_eq!(stats.total.skips, 1);
+        assert_eq!(stats.total.skip_rate, 0.25);
+        assert_eq!(stats.total.play_time, 400);
+        assert_eq!(stats.total.reactions.get("like"), Some(&2));
+
+        let tracks = stats
+            .top_tracks
+            .iter()
+            .map(|track| (track.url.as_deref().unwrap(), track.plays))
+            .collect::<Vec<_>>();
+        assert_eq!(tracks, [("a", 2), ("b", 1), ("c", 1)]);
+        assert_eq!(stats.top_channels.len(), 1);
+        assert_eq!(stats.top_channels[0].plays, 4);
+
+        // Records without a requester are left out
+        let requesters = stats
+            .top_requesters
+            .iter()
+            .map(|requester| (requester.name.as_str(), requester.plays))
+            .collect::<Vec<_>>();
+        assert_eq!(requesters, [("User 1", 2), ("User 2", 1)]);
+
+        assert_eq!(stats.busiest_hours.len(), 24);
+        assert_eq!(stats.busiest_hours[20].plays, 3);
+        assert_eq!(stats.busiest_hours[21].plays, 1);
+    }
+
+    #[test]
+    fn dates_are_inclusive() {
+        let records = [
+            record((2026, 9, 30), 23, "a", None),
+            record((2026, 10, 1), 0, "b", None),
+            record((2026, 10, 31), 23, "c", None),
+            record((2026, 11, 1), 0, "d", None),
+        ];
+        let query = StatsQuery::parse(Some("2026-10-01"), Some("2026-10-31"), None).unwrap();
+        let stats = compute_records(&records, query);
+        assert_eq!(stats.total.plays, 2);
+        let tracks = stats
+            .top_tracks
+            .iter()
+            .map(|track| track.url.as_deref().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(tracks, ["b", "c"]);
+    }
+
+    #[test]
+    fn interrupted_records_and_limit() {
+        let mut interrupted = record((2026, 10, 1), 12, "a", Some(1));
+        interrupted.interrupted = true;
+        let records = [
+            interrupted,
+            record((2026, 10, 1), 12, "b", Some(1)),
+            record((2026, 10, 1), 12, "c", Some(1)),
+            record((2026, 10, 1), 12, "c", Some(1)),
+        ];
+        let query = StatsQuery {
+            limit: 1,
+            ..StatsQuery::default()
+        };
+        let stats = compute_records(&records, query);
+        assert_eq!(stats.total.plays, 3);
+        assert_eq!(stats.top_tracks.len(), 1);
+        assert_eq!(stats.top_tracks[0].url.as_deref(), Some("c"));
+    }
+
+    #[test]
+    fn csv_fields_are_escaped() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field("a, b"), "\"a, b\"");
+        assert_eq!(csv_field("say \"hi\""), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_field("=HYPERLINK(\"x\")"), "\"'=HYPERLINK(\"\"x\"\")\"");
+        assert_eq!(csv_field("-1"), "'-1");
+        assert_eq!(csv_field("@me"), "'@me");
+    }
+}
@@ -0,0 +1,130 @@
+//! JSON files in the data directory for state changed at runtime: users, playlists and settings
+//!
+//! Changes are only marked while the state is locked, [`save_files`] writes them on a blocking
+//! thread.
+
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use log::error;
+use serde::{Serialize, de::DeserializeOwned};
+use smol::{Timer, lock::Mutex};
+
+use crate::AppState;
+
+/// Changes are written this often
+const SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Where a value is saved, and whether it changed since
+#[derive(Debug, Default)]
+pub struct StoreFile {
+    path: Option<PathBuf>,
+    dirty: bool,
+}
+
+/// Content taken under the lock, to be written without it
+pub struct PendingWrite {
+    path: PathBuf,
+    content: anyhow::Result<Vec<u8>>,
+}
+
+impl StoreFile {
+    /// Read `file` in `data_dir`, `what` names it in logs. Missing or malformed files give the default.
+    pub fn load<T: DeserializeOwned + Default>(
+        data_dir: &Path,
+        file: &str,
+        what: &str,
+    ) -> (T, StoreFile) {
+        let path = data_dir.join(file);
+        let value = match fs::read(&path) {
+            Ok(content) => match serde_json::from_slice(&content) {
+                Ok(value) => value,
+                Err(error) => {
+                    error!("Malformed {what} {}: {error}", path.display());
+                    T::default()
+                }
+            },
+            Err(error) if error.kind() == ErrorKind::NotFound => T::default(),
+            Err(error) => {
+                error!("Failed to read {what} {}: {error}", path.display());
+                T::default()
+            }
+        };
+        let file = StoreFile {
+            path: Some(path),
+            dirty: false,
+        };
+        (value, file)
+    }
+
+    /// Save at the next [`flush`]
+    pub fn mark_changed(&mut self) {
+        self.dirty = true;
+    }
+
+    /// Path to write to, if the value changed since the last call
+    pub fn take_changed(&mut self) -> Option<PathBuf> {
+        if !self.dirty {
+            return None;
+        }
+        self.dirty = false;
+        self.path.clone()
+    }
+}
+
+impl PendingWrite {
+    pub fn new(path: PathBuf, value: &impl Serialize) -> Self {
+        PendingWrite {
+            path,
+            content: serde_json::to_vec_pretty(value).map_err(anyhow::Error::from),
+        }
+    }
+
+    async fn write(self) {
+        let PendingWrite { path, content } = self;
+        let result = smol::unblock(move || {
+            let result = content.and_then(|content| write(&path, &content));
+            (path, result)
+        })
+        .await;
+        if let (path, Err(error)) = result {
+            error!("Failed to write {}: {error}", path.display());
+        }
+    }
+}
+
+fn write(path: &Path, content: &[u8]) -> anyhow::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, content)?;
+    Ok(())
+}
+
+/// Write every file that changed, the content is taken under the lock
+/// and written on a blocking thread
+pub async fn flush(state: &Mutex<AppState<'_>>) {
+    let writes = {
+        let mut state = state.lock().await;
+        [
+            state.users.take_changes(),
+            state.playlists.take_changes(),
+            state.settings.take_changes(),
+        ]
+    };
+    for write in writes.into_iter().flatten() {
+        write.write().await;
+    }
+}
+
+/// Save changed files every [`SAVE_INTERVAL`]
+pub async fn save_files(state: &Mutex<AppState<'_>>) {
+    loop {
+        Timer::after(SAVE_INTERVAL).await;
+        flush(state).await;
+    }
+}
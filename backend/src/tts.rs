@@ -0,0 +1,100 @@
+use std::{fmt, io::ErrorKind, path::Path, process::ExitStatus, time::Duration};
+
+use smol::{
+    Timer,
+    future::FutureExt,
+    io::AsyncWriteExt,
+    process::{Command, Stdio},
+};
+
+use crate::config::TtsConfig;
+
+#[derive(Debug)]
+pub enum TtsError {
+    /// `tts.command` is empty
+    NoCommand,
+    /// The TTS program is not found
+    NotInstalled(String),
+    /// Failed to run the TTS program for other reasons
+    Spawn(std::io::Error),
+    /// The TTS program did not finish within the timeout and was killed
+    Timeout(Duration),
+    Failed {
+        status: ExitStatus,
+        stderr: String,
+    },
+}
+
+impl fmt::Display for TtsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TtsError::NoCommand => write!(f, "tts.command is empty"),
+            TtsError::NotInstalled(program) => {
+                write!(f, "{program} is not installed or not in PATH")
+            }
+            TtsError::Spawn(error) => write!(f, "Failed to run TTS program: {error}"),
+            TtsError::Timeout(timeout) => {
+                write!(f, "TTS program timed out after {}s", timeout.as_secs())
+            }
+            TtsError::Failed { status, stderr } => {
+                write!(f, "TTS program failed: {status}\n{stderr}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for TtsError {}
+
+/// Speak `text` into the wav file `output` with the program of `tts.command`
+///
+/// The child process is killed when the timeout is reached, or when the returned future is dropped
+pub async fn synthesize(config: &TtsConfig, text: &str, output: &Path) -> Result<(), TtsError> {
+    let (program, args) = config.command.split_first().ok_or(TtsError::NoCommand)?;
+    let output = output.to_string_lossy();
+    let text_in_args = args.iter().any(|arg| arg.contains("{text}"));
+    let args = args
+        .iter()
+        .map(|arg| arg.replace("{text}", text).replace("{output}", &output));
+
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(if text_in_args {
+            Stdio::null()
+        } else {
+            Stdio::piped()
+        })
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|error| match error.kind() {
+            ErrorKind::NotFound => TtsError::NotInstalled(program.clone()),
+            _ => TtsError::Spawn(error),
+        })?;
+
+    let timeout = config.timeout();
+    let output = async {
+        if let Some(mut stdin) = child.stdin.take() {
+            // Dropping stdin closes it, so the program knows the text is complete
+            stdin
+                .write_all(text.as_bytes())
+                .await
+                .map_err(TtsError::Spawn)?;
+        }
+        child.output().await.map_err(TtsError::Spawn)
+    }
+    .or(async {
+        Timer::after(timeout).await;
+        Err(TtsError::Timeout(timeout))
+    })
+    .await?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+        return Err(TtsError::Failed {
+            status: output.status,
+            stderr,
+        });
+    }
+    Ok(())
+}
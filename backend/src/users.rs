@@ -0,0 +1,171 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::store::{PendingWrite, StoreFile};
+
+const USERS_FILE: &str = "users.json";
+const MAX_TOKEN_LEN: usize = 128;
+const MAX_NICKNAME_LEN: usize = 32;
+/// New users a single address can create per [`NEW_USER_WINDOW`]
+const MAX_NEW_USERS: usize = 10;
+const NEW_USER_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+/// Browsers that said hello, by the token they keep in local storage,
+/// saved to `users.json` in the data directory
+///
+/// Tokens are secrets, other clients only ever see the `id`
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Users {
+    by_token: HashMap<String, User>,
+    next_id: u64,
+    #[serde(skip)]
+    file: StoreFile,
+    /// When each address created its recent users
+    #[serde(skip)]
+    created_by_addr: HashMap<IpAddr, Vec<Instant>>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct User {
+    pub id: u64,
+    pub nickname: Option<String>,
+    /// Banned users can't add songs
+    #[serde(default)]
+    pub banned: bool,
+    /// Address the user last said hello from, a ban applies to it too
+    #[serde(default)]
+    pub addr: Option<IpAddr>,
+}
+
+#[derive(Debug)]
+pub enum IdentifyError {
+    /// Empty or too long
+    InvalidToken,
+    /// The address created too many users lately
+    TooManyUsers,
+}
+
+impl Users {
+    pub fn load(data_dir: &Path) -> Users {
+        let (users, file) = StoreFile::load(data_dir, USERS_FILE, "users");
+        Users { file, ..users }
+    }
+
+    /// Written by [`store::save_files`](crate::store::save_files), not while the state is locked
+    fn save(&mut self) {
+        self.file.mark_changed();
+    }
+
+    /// Content of `users.json` if it changed since the last call
+    pub fn take_changes(&mut self) -> Option<PendingWrite> {
+        let path = self.file.take_changed()?;
+        Some(PendingWrite::new(path, self))
+    }
+
+    /// The user of `token` saying hello from `addr`, a new one is created the first time
+    /// a token is seen
+    pub fn identify(&mut self, token: &str, addr: IpAddr) -> Result<&User, IdentifyError> {
+        if token.is_empty() || token.len() > MAX_TOKEN_LEN {
+            return Err(IdentifyError::InvalidToken);
+        }
+        if !self.by_token.contains_key(token) {
+            // Keeps a script from growing the file, or a banned user from starting over
+            let created = self.created_by_addr.entry(addr).or_default();
+            created.retain(|at| at.elapsed() < NEW_USER_WINDOW);
+            if created.len() >= MAX_NEW_USERS {
+                warn!("{addr} created too many users, hello refused");
+                return Err(IdentifyError::TooManyUsers);
+            }
+            created.push(Instant::now());
+            let user = User {
+                id: self.next_id,
+                nickname: None,
+                banned: false,
+                addr: None,
+            };
+            self.next_id += 1;
+            self.by_token.insert(token.to_owned(), user);
+            self.save();
+        }
+        let user = self
+            .by_token
+            .get_mut(token)
+            .ok_or(IdentifyError::InvalidToken)?;
+        if user.addr != Some(addr) {
+            user.addr = Some(addr);
+            self.save();
+        }
+        self.by_token.get(token).ok_or(IdentifyError::InvalidToken)
+    }
+
+    pub fn get(&self, id: u64) -> Option<&User> {
+        self.by_token.values().find(|user| user.id == id)
+    }
+
+    fn get_mut(&mut self, id: u64) -> Option<&mut User> {
+        self.by_token.values_mut().find(|user| user.id == id)
+    }
+
+    pub fn nickname(&self, id: u64) -> Option<&str> {
+        self.get(id).and_then(|user| user.nickname.as_deref())
+    }
+
+    /// Set the nickname of user `id`, it is trimmed and cut to a reasonable length,
+    /// an empty one removes it
+    pub fn set_nickname(&mut self, id: u64, nickname: &str) -> Option<&User> {
+        let nickname = nickname.trim();
+        let nickname = (!nickname.is_empty())
+            .then(|| nickname.chars().take(MAX_NICKNAME_LEN).collect::<String>());
+        self.get_mut(id)?.nickname = nickname;
+        self.save();
+        self.get(id)
+    }
+
+    /// Returns `false` if there is no user `id`
+    pub fn set_banned(&mut self, id: u64, banned: bool) -> bool {
+        let Some(user) = self.get_mut(id) else {
+            return false;
+        };
+        user.banned = banned;
+        self.save();
+        true
+    }
+
+    /// Whether user `id` is banned, or last said hello from the address of a banned user
+    pub fn is_banned(&self, id: u64) -> bool {
+        let Some(user) = self.get(id) else {
+            return false;
+        };
+        user.banned
+            || user.addr.is_some_and(|addr| {
+                self.by_token
+                    .values()
+                    .any(|other| other.banned && other.addr == Some(addr))
+            })
+    }
+
+    /// Every user, by id
+    pub fn list(&self) -> Vec<&User> {
+        let mut users = self.by_token.values().collect::<Vec<_>>();
+        users.sort_by_key(|user| user.id);
+        users
+    }
+}
+
+impl IdentifyError {
+    /// Message that can be shown to the client
+    pub fn user_message(&self) -> &'static str {
+        match self {
+            IdentifyError::InvalidToken => "Invalid browser token",
+            IdentifyError::TooManyUsers => "Too many new users from this device, try again later",
+        }
+    }
+}
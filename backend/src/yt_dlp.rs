@@ -1,8 +1,21 @@
-use serde::Deserialize;
-use smol::process::{Command, Stdio};
+use std::{
+    fmt,
+    io::ErrorKind,
+    process::ExitStatus,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Deserializer};
+use smol::{
+    Timer,
+    future::FutureExt,
+    process::{Command, Stdio},
+};
+
+use crate::metrics::METRICS;
 
 pub enum YtdlpResult {
-    Single(YoutubeInfo),
+    Single(Box<YoutubeInfo>),
     Playlist(Vec<YoutubePlaylistEntry>),
 }
 
@@ -12,17 +25,25 @@ pub struct YoutubeInfo {
     pub id: String,
     pub title: String,
     pub description: Option<String>,
-    pub channel: String,
-    pub channel_url: String,
-    pub duration: u32,
+    pub channel: Option<String>,
+    pub channel_url: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub duration: Option<u32>,
     pub playlist: Option<String>,
-    pub thumbnail: String,
+    pub thumbnail: Option<String>,
     pub formats: Vec<MediaFormat>,
+    /// yt-dlp extractor name, e.g. `youtube`, `soundcloud`
+    pub extractor: String,
+    /// Human readable extractor name, e.g. `Youtube`, `Soundcloud`
+    pub extractor_key: String,
+    /// Canonical URL of the page
+    pub webpage_url: String,
 }
 
 #[allow(unused)]
 #[derive(Debug, Clone, Deserialize)]
 pub struct MediaFormat {
+    pub format_id: Option<String>,
     pub format_note: Option<String>,
     pub quality: Option<f32>,
     pub vcodec: Option<String>,
@@ -31,26 +52,321 @@ pub struct MediaFormat {
     pub audio_ext: String,
     pub ext: String,
     pub url: String,
+    /// Audio bitrate in kbps
+    pub abr: Option<f32>,
+    /// Total bitrate in kbps
+    pub tbr: Option<f32>,
+}
+
+impl MediaFormat {
+    /// Bitrate in kbps, as much as we need to download
+    pub fn bitrate(&self) -> Option<f32> {
+        self.tbr.or(self.abr)
+    }
 }
 
 #[allow(unused)]
 #[derive(Debug, Clone, Deserialize)]
 pub struct YoutubePlaylistEntry {
     pub id: String,
-    pub title: String,
+    pub title: Option<String>,
     pub description: Option<String>,
-    pub channel: String,
-    pub channel_url: String,
-    pub duration: u32,
+    pub channel: Option<String>,
+    pub channel_url: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_duration")]
+    pub duration: Option<u32>,
     pub playlist: Option<String>,
+    /// URL of the entry itself
+    ///
+    /// `webpage_url` of a flat playlist entry points to the playlist, so it is not captured here
+    pub url: String,
+    /// Extractor that will handle `url`, e.g. `Youtube`, `Soundcloud`
+    pub ie_key: Option<String>,
+    /// Extractor of the playlist
+    pub extractor: Option<String>,
+}
+
+// Some extractors report duration as a float
+fn deserialize_duration<'de, D>(deserializer: D) -> Result<Option<u32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let duration = Option::<f64>::deserialize(deserializer)?;
+    Ok(duration.map(|duration| duration.round() as u32))
+}
+
+#[derive(Debug)]
+pub enum YtdlpError {
+    /// The URL is empty or would be parsed as an option by yt-dlp
+    InvalidUrl(String),
+    /// The `yt-dlp` executable is not found
+    NotInstalled,
+    /// Failed to run `yt-dlp` for other reasons
+    Spawn(std::io::Error),
+    /// yt-dlp did not finish within the timeout and was killed
+    Timeout(Duration),
+    /// No extractor can handle the URL
+    Unsupported,
+    Private,
+    AgeRestricted,
+    /// Removed, region locked, or otherwise not available
+    Unavailable,
+    /// The site wants us to prove we are not a bot, usually needs cookies
+    BotCheck,
+    /// Too many requests, the site asked us to slow down
+    RateLimited,
+    /// DNS failures, connection resets, 5xx, ...
+    Network(String),
+    /// yt-dlp failed with an error we don't recognize
+    Failed {
+        status: ExitStatus,
+        stderr: String,
+    },
+    /// yt-dlp succeeded but the output can't be parsed
+    InvalidOutput(String),
+}
+
+/// How the caller should react to a [`YtdlpError`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorAction {
+    /// Transient failure, try again later
+    Retry,
+    /// The link is at fault, tell the requester
+    Report,
+    /// Something is wrong with the system, someone needs to take a look
+    Alert,
+}
+
+impl YtdlpError {
+    /// Classify a failed run by the last `ERROR:` line of its stderr
+    ///
+    /// Transport errors are checked first: yt-dlp wraps them in messages like
+    /// `Unable to download webpage: <urlopen error ...>` that must not look like a broken link.
+    fn classify(status: ExitStatus, stderr: String) -> YtdlpError {
+        let reason = error_reason(&stderr).to_lowercase();
+        let has = |patterns: &[&str]| patterns.iter().any(|pattern| reason.contains(pattern));
+        let starts = |prefixes: &[&str]| prefixes.iter().any(|prefix| reason.starts_with(prefix));
+
+        if has(&[
+            "transporterror",
+            "urlopen error",
+            "temporary failure in name resolution",
+            "name or service not known",
+            "network is unreachable",
+            "connection reset by peer",
+            "connection refused",
+            "read timed out",
+            "the read operation timed out",
+            "remote end closed connection",
+            "incompleteread",
+        ]) {
+            return YtdlpError::Network(stderr);
+        }
+        match http_status(&reason) {
+            Some(429) => return YtdlpError::RateLimited,
+            Some(404 | 410) => return YtdlpError::Unavailable,
+            Some(500..=599) => return YtdlpError::Network(stderr),
+            _ => {}
+        }
+
+        if starts(&["unsupported url:"]) || has(&["is not a valid url"]) {
+            YtdlpError::Unsupported
+        } else if starts(&["private video", "this video is private"]) {
+            YtdlpError::Private
+        } else if starts(&[
+            "sign in to confirm your age",
+            "this video may be inappropriate for some users",
+        ]) {
+            YtdlpError::AgeRestricted
+        } else if starts(&["sign in to confirm you"]) && has(&["not a bot"]) {
+            YtdlpError::BotCheck
+        } else if starts(&[
+            "video unavailable",
+            "this video is not available",
+            "this video has been removed",
+            "this video is no longer available",
+        ]) || has(&["has not made this video available in your country"])
+        {
+            YtdlpError::Unavailable
+        } else {
+            YtdlpError::Failed { status, stderr }
+        }
+    }
+
+    pub fn action(&self) -> ErrorAction {
+        match self {
+            YtdlpError::Timeout(_) | YtdlpError::RateLimited | YtdlpError::Network(_) => {
+                ErrorAction::Retry
+            }
+            YtdlpError::InvalidUrl(_)
+            | YtdlpError::Unsupported
+            | YtdlpError::Private
+            | YtdlpError::AgeRestricted
+            | YtdlpError::Unavailable => ErrorAction::Report,
+            YtdlpError::NotInstalled
+            | YtdlpError::Spawn(_)
+            | YtdlpError::BotCheck
+            | YtdlpError::Failed { .. }
+            | YtdlpError::InvalidOutput(_) => ErrorAction::Alert,
+        }
+    }
+
+    /// Short name of the variant, used as a metrics label
+    pub fn kind(&self) -> &'static str {
+        match self {
+            YtdlpError::InvalidUrl(_) => "invalid_url",
+            YtdlpError::NotInstalled => "not_installed",
+            YtdlpError::Spawn(_) => "spawn",
+            YtdlpError::Timeout(_) => "timeout",
+            YtdlpError::Unsupported => "unsupported",
+            YtdlpError::Private => "private",
+            YtdlpError::AgeRestricted => "age_restricted",
+            YtdlpError::Unavailable => "unavailable",
+            YtdlpError::BotCheck => "bot_check",
+            YtdlpError::RateLimited => "rate_limited",
+            YtdlpError::Network(_) => "network",
+            YtdlpError::Failed { .. } => "failed",
+            YtdlpError::InvalidOutput(_) => "invalid_output",
+        }
+    }
+
+    /// Message that can be shown to the requester
+    pub fn user_message(&self) -> String {
+        match self {
+            YtdlpError::InvalidUrl(_) => "This is not a valid link".to_owned(),
+            YtdlpError::Unsupported => "This site is not supported".to_owned(),
+            YtdlpError::Private => "This video is private".to_owned(),
+            YtdlpError::AgeRestricted => "This video is age-restricted".to_owned(),
+            YtdlpError::Unavailable => "This video is not available".to_owned(),
+            YtdlpError::Timeout(timeout) => {
+                format!("Timed out after {}s", timeout.as_secs())
+            }
+            YtdlpError::RateLimited | YtdlpError::Network(_) => {
+                "Network problem, please try again later".to_owned()
+            }
+            YtdlpError::NotInstalled
+            | YtdlpError::Spawn(_)
+            | YtdlpError::BotCheck
+            | YtdlpError::Failed { .. }
+            | YtdlpError::InvalidOutput(_) => {
+                "Something is wrong with the player, please tell the admin".to_owned()
+            }
+        }
+    }
+}
+
+/// Message of the last `ERROR:` line, without the `[extractor] id: ` prefix
+fn error_reason(stderr: &str) -> &str {
+    let Some(line) = stderr
+        .lines()
+        .rev()
+        .find_map(|line| line.trim().strip_prefix("ERROR:"))
+    else {
+        return stderr;
+    };
+    let line = line.trim_start();
+    match line
+        .strip_prefix('[')
+        .and_then(|rest| rest.split_once("] "))
+    {
+        // The id is left out of the message for errors about the URL itself
+        Some((_, rest)) => rest.split_once(": ").map_or(rest, |(_, message)| message),
+        None => line,
+    }
+}
+
+/// Status of an `HTTP Error 404: Not Found` in the message
+fn http_status(reason: &str) -> Option<u16> {
+    let (_, rest) = reason.split_once("http error ")?;
+    rest.get(..3)?.parse().ok()
+}
+
+impl fmt::Display for YtdlpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            YtdlpError::InvalidUrl(url) => write!(f, "Invalid URL :{url}"),
+            YtdlpError::NotInstalled => write!(f, "yt-dlp is not installed or not in PATH"),
+            YtdlpError::Spawn(error) => write!(f, "Failed to run yt-dlp: {error}"),
+            YtdlpError::Timeout(timeout) => {
+                write!(f, "yt-dlp timed out after {}s", timeout.as_secs())
+            }
+            YtdlpError::Unsupported => write!(f, "Unsupported URL"),
+            YtdlpError::Private => write!(f, "Private video"),
+            YtdlpError::AgeRestricted => write!(f, "Age-restricted video"),
+            YtdlpError::Unavailable => write!(f, "Video unavailable"),
+            YtdlpError::BotCheck => write!(f, "Site requires bot check (cookies needed?)"),
+            YtdlpError::RateLimited => write!(f, "Rate limited by the site"),
+            YtdlpError::Network(stderr) => write!(f, "Network error\n{stderr}"),
+            YtdlpError::Failed { status, stderr } => {
+                write!(f, "Call to yt-dlp failed: {status}\n{stderr}")
+            }
+            YtdlpError::InvalidOutput(error) => write!(f, "Invalid yt-dlp output: {error}"),
+        }
+    }
+}
+
+impl std::error::Error for YtdlpError {}
+
+impl From<serde_json::Error> for YtdlpError {
+    fn from(error: serde_json::Error) -> Self {
+        YtdlpError::InvalidOutput(error.to_string())
+    }
+}
+
+impl From<std::str::Utf8Error> for YtdlpError {
+    fn from(error: std::str::Utf8Error) -> Self {
+        YtdlpError::InvalidOutput(error.to_string())
+    }
+}
+
+/// Check that yt-dlp can be run, returns its version
+pub async fn ytdlp_version() -> Result<String, YtdlpError> {
+    const TIMEOUT: Duration = Duration::from_secs(5);
+    let child = Command::new("yt-dlp")
+        .arg("--version")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|error| match error.kind() {
+            ErrorKind::NotFound => YtdlpError::NotInstalled,
+            _ => YtdlpError::Spawn(error),
+        })?;
+
+    let output = async { child.output().await.map_err(YtdlpError::Spawn) }
+        .or(async {
+            Timer::after(TIMEOUT).await;
+            Err(YtdlpError::Timeout(TIMEOUT))
+        })
+        .await?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+        return Err(YtdlpError::classify(output.status, stderr));
+    }
+    Ok(std::str::from_utf8(&output.stdout)?.trim().to_owned())
 }
 
-pub async fn get_ytdlp(url: String) -> anyhow::Result<YtdlpResult> {
+/// Run yt-dlp on `url`
+///
+/// The child process is killed when `timeout` is reached, or when the returned future is dropped
+pub async fn get_ytdlp(url: String, timeout: Duration) -> Result<YtdlpResult, YtdlpError> {
+    let start = Instant::now();
+    let result = run_ytdlp(url, timeout).await;
+    METRICS.ytdlp_duration.observe(start.elapsed());
+    if let Err(error) = &result {
+        METRICS.ytdlp_failed(error.kind());
+    }
+    result
+}
+
+async fn run_ytdlp(url: String, timeout: Duration) -> Result<YtdlpResult, YtdlpError> {
     if matches!(url.chars().next(), None | Some('-')) {
-        return Err(anyhow::anyhow!("Invalid URL :{}", url));
+        return Err(YtdlpError::InvalidUrl(url));
     }
 
-    let output = Command::new("yt-dlp")
+    let child = Command::new("yt-dlp")
         .arg("-j")
         .arg("--flat-playlist")
         .arg("--skip-download")
@@ -59,16 +375,23 @@ pub async fn get_ytdlp(url: String) -> anyhow::Result<YtdlpResult> {
         .stdin(Stdio::null())
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
-        .spawn()?
-        .output()
+        .kill_on_drop(true)
+        .spawn()
+        .map_err(|error| match error.kind() {
+            ErrorKind::NotFound => YtdlpError::NotInstalled,
+            _ => YtdlpError::Spawn(error),
+        })?;
+
+    let output = async { child.output().await.map_err(YtdlpError::Spawn) }
+        .or(async {
+            Timer::after(timeout).await;
+            Err(YtdlpError::Timeout(timeout))
+        })
         .await?;
 
     if !output.status.success() {
-        return Err(anyhow::anyhow!(
-            "Call to yt-dlp failed: {}\n{}",
-            output.status,
-            std::str::from_utf8(&output.stderr)?
-        ));
+        let stderr = String::from_utf8_lossy(&output.stderr).into_owned();
+        return Err(YtdlpError::classify(output.status, stderr));
     }
 
     let result = std::str::from_utf8(&output.stdout)?;
@@ -80,7 +403,9 @@ pub async fn get_ytdlp(url: String) -> anyhow::Result<YtdlpResult> {
 
     let is_playlist = if let Some(first) = list.first() {
         let serde_json::Value::Object(map) = first else {
-            return Err(anyhow::anyhow!("yt-dlp did not return a JSON Object"));
+            return Err(YtdlpError::InvalidOutput(
+                "yt-dlp did not return a JSON Object".to_owned(),
+            ));
         };
 
         map.get("playlist").is_some_and(|x| !x.is_null())
@@ -100,3 +425,126 @@ pub async fn get_ytdlp(url: String) -> anyhow::Result<YtdlpResult> {
         Ok(YtdlpResult::Single(info))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::process::ExitStatusExt;
+
+    use super::*;
+
+    fn classify(stderr: &str) -> YtdlpError {
+        YtdlpError::classify(ExitStatus::from_raw(1 << 8), stderr.to_owned())
+    }
+
+    #[test]
+    fn network_errors_are_retried() {
+        for stderr in [
+            "ERROR: [youtube] dQw4w9WgXcQ: Unable to download API page: <urlopen error [Errno -3] \
+             Temporary failure in name resolution> (caused by TransportError('<urlopen error \
+             [Errno -3] Temporary failure in name resolution>'))",
+            "ERROR: [youtube] dQw4w9WgXcQ: Unable to download webpage: <urlopen error [Errno 101] \
+             Network is unreachable> (caused by TransportError('<urlopen error [Errno 101] \
+             Network is unreachable>'))",
+            "ERROR: [soundcloud] 123: Unable to download JSON metadata: \
+             ('Connection aborted.', ConnectionResetError(104, 'Connection reset by peer'))",
+            "ERROR: [youtube] dQw4w9WgXcQ: Unable to download webpage: The read operation timed out",
+            "ERROR: [youtube] dQw4w9WgXcQ: Unable to download webpage: HTTP Error 503: \
+             Service Unavailable (caused by <HTTPError 503: Service Unavailable>)",
+        ] {
+            let error = classify(stderr);
+            assert!(
+                matches!(error, YtdlpError::Network(_)),
+                "{stderr}: {error:?}"
+            );
+            assert_eq!(error.action(), ErrorAction::Retry);
+        }
+        assert!(matches!(
+            classify(
+                "ERROR: [youtube] dQw4w9WgXcQ: Unable to download webpage: HTTP Error 429: \
+                 Too Many Requests (caused by <HTTPError 429: Too Many Requests>)"
+            ),
+            YtdlpError::RateLimited
+        ));
+    }
+
+    #[test]
+    fn link_problems_are_reported() {
+        let cases = [
+            (
+                "ERROR: Unsupported URL: https://example.com/",
+                "unsupported",
+            ),
+            (
+                "ERROR: [generic] 'hello' is not a valid URL. Set --default-search \"ytsearch\" \
+                 (or run  yt-dlp \"ytsearch:hello\" ) to search YouTube",
+                "unsupported",
+            ),
+            (
+                "ERROR: [youtube] dQw4w9WgXcQ: Private video. Sign in if you've been granted \
+                 access to this video. Use --cookies-from-browser or --cookies for the \
+                 authentication.",
+                "private",
+            ),
+            (
+                "ERROR: [youtube] dQw4w9WgXcQ: Sign in to confirm your age. This video may be \
+                 inappropriate for some users. Use --cookies-from-browser or --cookies for the \
+                 authentication.",
+                "age_restricted",
+            ),
+            (
+                "ERROR: [youtube] dQw4w9WgXcQ: Video unavailable. This video has been removed \
+                 by the uploader",
+                "unavailable",
+            ),
+            (
+                "ERROR: [youtube] dQw4w9WgXcQ: Video unavailable. The uploader has not made this \
+                 video available in your country",
+                "unavailable",
+            ),
+            (
+                "ERROR: [vimeo] 12345: Unable to download webpage: HTTP Error 404: Not Found \
+                 (caused by <HTTPError 404: Not Found>)",
+                "unavailable",
+            ),
+        ];
+        for (stderr, kind) in cases {
+            let error = classify(stderr);
+            assert_eq!(error.kind(), kind, "{stderr}");
+            assert_eq!(error.action(), ErrorAction::Report);
+        }
+    }
+
+    #[test]
+    fn system_problems_alert_the_admin() {
+        let cases = [
+            (
+                "ERROR: [youtube] dQw4w9WgXcQ: Sign in to confirm you\u{2019}re not a bot. Use \
+                 --cookies-from-browser or --cookies for the authentication.",
+                "bot_check",
+            ),
+            // Not a broken link, the format selection is at fault
+            (
+                "ERROR: [youtube] dQw4w9WgXcQ: Requested format is not available. Use \
+                 --list-formats for a list of available formats",
+                "failed",
+            ),
+            (
+                "ERROR: unable to download video data: HTTP Error 403: Forbidden",
+                "failed",
+            ),
+        ];
+        for (stderr, kind) in cases {
+            let error = classify(stderr);
+            assert_eq!(error.kind(), kind, "{stderr}");
+            assert_eq!(error.action(), ErrorAction::Alert);
+        }
+    }
+
+    #[test]
+    fn only_the_last_error_counts() {
+        let stderr = "WARNING: [youtube] Video unavailable in one client, trying another\n\
+                      ERROR: [youtube] dQw4w9WgXcQ: Private video. Sign in if you've been granted \
+                      access to this video";
+        assert_eq!(classify(stderr).kind(), "private");
+    }
+}
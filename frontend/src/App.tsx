@@ -1,4 +1,4 @@
-import { useCallback, useState } from 'react';
+import { useCallback, useEffect, useRef, useState } from 'react';
 import {
   AppBar,
   Box,
@@ -14,19 +14,47 @@ import {
   Toolbar,
   Typography,
 } from '@mui/material';
-import { useSession } from './session.ts';
+import { browserToken, useSession } from './session.ts';
 import Player from './Player.tsx';
 import ThemeToggle from './ThemeToggle.tsx';
 import { get_theme, ThemeId } from './theme.ts';
-import { Link } from '@mui/icons-material';
+import { Close, Download, Link, PlaylistAdd, Replay, Upload } from '@mui/icons-material';
 import CustomSnackbar from './CustomSnackbar.tsx';
 import ChangelogView from './ChangelogView.tsx';
+import Reactions from './Reactions.tsx';
+
+type Requester = {
+  id: number,
+  nickname?: string,
+};
 
 type ListEntry = {
+  id?: number,
+  state?: string,
+  error?: string,
   fetched: boolean,
   title: string,
-  url: string,
+  url?: string,
+  source?: string,
   time: number,
+  requester?: Requester | null,
+  reactions?: Record<string, number>,
+  position?: number,
+  // Unix time the song playing started at, null while paused
+  started_at?: number | null,
+};
+
+type SavedPlaylist = {
+  id: number,
+  name: string,
+  songs: Array<unknown>,
+};
+
+type ScheduleRule = {
+  name: string,
+  to: string,
+  pause: boolean,
+  max_volume?: number,
 };
 
 function copyToClipboard(textToCopy: string) {
@@ -55,42 +83,146 @@ function copyToClipboard(textToCopy: string) {
   }
 }
 
+function format_duration(secs: number): string {
+  const second = Math.floor(secs % 60).toString().padStart(2, "0");
+  const minute = Math.floor(secs / 60);
+  if (minute >= 60) {
+    const minute2 = Math.floor(minute % 60).toString().padStart(2, "0");
+    const hour = Math.floor(minute / 60);
+    return `${hour}:${minute2}:${second}`;
+  }
+  return `${minute}:${second}`;
+}
+
+type QueueOp =
+  | { op: "remove", id: number }
+  | { op: "insert", index: number, entry: ListEntry }
+  | { op: "move", id: number, index: number }
+  | { op: "update", entry: ListEntry };
+
+// Apply a `queue_patch` message to the last `queue` message
+function apply_queue_patch(queue: Record<string, unknown>, patch: Record<string, unknown>) {
+  const entries = [...(queue["queue"] as Array<ListEntry>)];
+  for (const op of patch["ops"] as Array<QueueOp>) {
+    if (op.op == "remove") {
+      entries.splice(entries.findIndex((entry) => entry.id == op.id), 1);
+    } else if (op.op == "insert") {
+      entries.splice(op.index, 0, op.entry);
+    } else if (op.op == "move") {
+      const [entry] = entries.splice(entries.findIndex((entry) => entry.id == op.id), 1);
+      entries.splice(op.index, 0, entry);
+    } else if (op.op == "update") {
+      entries[entries.findIndex((entry) => entry.id == op.entry.id)] = op.entry;
+    }
+  }
+  return {
+    ...queue,
+    ...(patch["fields"] as Record<string, unknown>),
+    queue: entries,
+    seq: patch["seq"],
+  };
+}
+
+function download(filename: string, content: string) {
+  const url = URL.createObjectURL(new Blob([content]));
+  const link = document.createElement("a");
+  link.href = url;
+  link.download = filename;
+  link.click();
+  URL.revokeObjectURL(url);
+}
+
 function App() {
   const [theme, setTheme] = useState(ThemeId.Dark);
   // player
   const [playing, setPlaying] = useState(false);
   const [volume, setVolume] = useState(0);
+  const [schedule, setSchedule] = useState<ScheduleRule | null>(null);
   // queue
   const [now_playing, setNowPlaying] = useState<ListEntry | null>(null);
+  const [reactions, setReactions] = useState<Record<string, number>>({});
+  const [playlists, setPlaylists] = useState<Array<SavedPlaylist>>([]);
+  const [fallback, setFallback] = useState<number | null>(null);
   const [recv, setRecv] = useState<Array<ListEntry>>([]);
+  const [total_time, setTotalTime] = useState<[number, boolean]>([0, true]);
+  // Ids of the queue entries in the order they will play
+  const [play_order, setPlayOrder] = useState<Array<number>>([]);
+  // Ticks every second so ETAs count down
+  const [now, setNow] = useState(Date.now());
+  // Last queue from the server, patches apply to it
+  const queue_msg = useRef<Record<string, unknown> | null>(null);
   const [yt_link, setYtLink] = useState("");
+  const [nickname, setNickname] = useState("");
   const [snackbar_message, setSnackbarMessage] =
     useState<string | undefined>(undefined);
   const [snackbar_key, setSnackbarKey] = useState(0);
+  useEffect(() => {
+    const timer = setInterval(() => setNow(Date.now()), 1000);
+    return () => clearInterval(timer);
+  }, []);
   const session = useSession(
     // on open
-    useCallback(() => { }, []),
+    useCallback((event: Event) => {
+      const msg = {
+        msg: "hello",
+        token: browserToken(),
+      };
+      const ws = event.target as WebSocket;
+      ws.send(JSON.stringify(msg));
+      ws.send(JSON.stringify({ msg: "playlists" }));
+    }, []),
     // on error
     useCallback(() => { }, []),
     // on message
     useCallback((event) => {
       try {
         const body = JSON.parse(event.data);
+        let queue_body = null;
         if (body["msg"] == "queue") {
+          queue_body = body;
+        } else if (body["msg"] == "queue_patch") {
+          const seq = queue_msg.current?.["seq"] as number | undefined;
+          if (seq === undefined || body["seq"] > seq + 1) {
+            // Missed an update
+            (event.target as WebSocket).send(JSON.stringify({ msg: "queue_resync" }));
+          } else if (body["seq"] == seq + 1) {
+            queue_body = apply_queue_patch(queue_msg.current!, body);
+          }
+        }
+        if (queue_body !== null) {
+          queue_msg.current = queue_body;
+          const body = queue_body;
           const msg_now_playing =
             body["now_playing"] as ListEntry | undefined;
           const queue = body["queue"] as Array<ListEntry>;
-          if (msg_now_playing !== undefined) {
+          if (msg_now_playing !== undefined && msg_now_playing !== null) {
             setNowPlaying(msg_now_playing!);
+            setReactions(msg_now_playing.reactions ?? {});
           } else {
             setNowPlaying(null);
+            setReactions({});
           }
           setRecv(queue);
+          setPlayOrder((body["play_order"] as Array<number>) ?? []);
+          setTotalTime([
+            (body["total_time"] as number) ?? 0,
+            (body["total_exact"] as boolean) ?? true,
+          ]);
         } else if (body["msg"] == "player") {
           const playing = body["playing"] as boolean;
           const volume = body["volume"] as number;
           setPlaying(playing);
           setVolume(volume);
+          setSchedule(body["schedule"] as ScheduleRule | null);
+        } else if (body["msg"] == "reactions") {
+          setReactions(body["reactions"] ?? {});
+        } else if (body["msg"] == "hello" || body["msg"] == "nickname") {
+          setNickname(body["nickname"] ?? "");
+        } else if (body["msg"] == "playlists") {
+          setPlaylists(body["playlists"] as Array<SavedPlaylist>);
+          setFallback(body["fallback"] as number | null);
+        } else if (body["msg"] == "export") {
+          download(`queue.${body["format"]}`, body["content"] as string);
         } else if (body["msg"] == "snackbar") {
           const msg = body["text"] as string;
           display_snackbar(msg);
@@ -100,7 +232,12 @@ function App() {
       }
     }, [recv]),
     // on close
-    useCallback(() => { }, []),
+    useCallback((event: CloseEvent) => {
+      // 1001 (going away): the server is shutting down
+      if (event.code == 1001) {
+        display_snackbar("Server is restarting, reconnecting...");
+      }
+    }, []),
   );
 
   function on_theme_toggle() {
@@ -120,6 +257,38 @@ function App() {
     session.send(JSON.stringify(msg));
   }
 
+  function on_reaction(reaction: string) {
+    const msg = {
+      msg: "react",
+      reaction: reaction,
+    };
+    session.send(JSON.stringify(msg));
+  }
+
+  function on_export() {
+    const msg = {
+      msg: "export",
+      format: "m3u",
+    };
+    session.send(JSON.stringify(msg));
+  }
+
+  async function on_import(file: File) {
+    const msg = {
+      msg: "import",
+      content: await file.text(),
+    };
+    session.send(JSON.stringify(msg));
+  }
+
+  function on_nickname_submit() {
+    const msg = {
+      msg: "nickname",
+      nickname: nickname,
+    };
+    session.send(JSON.stringify(msg));
+  }
+
   function display_snackbar(message: string) {
     setSnackbarMessage(message);
     setSnackbarKey(new Date().getTime());
@@ -147,35 +316,89 @@ function App() {
     session.send(JSON.stringify(msg));
   }
 
-  function gen_queue_entry(item: ListEntry) {
-    let time = null;
-    if (item.time) {
-      const second = Math.floor(item.time % 60).toString().padStart(2, "0");
-      const minute = Math.floor(item.time / 60);
-      if (minute >= 60) {
-        const minute2 = Math.floor(minute % 60).toString().padStart(2, "0");
-        const hour = Math.floor(minute / 60);
-        time = `${hour}:${minute2}:${second}`;
+  function on_queue_entry_action(action: string, id: number) {
+    const msg = {
+      msg: action,
+      id: id,
+    };
+    session.send(JSON.stringify(msg));
+  }
+
+  // Seconds left of the song playing, frozen while paused
+  function time_left(): number {
+    if (!now_playing?.time) {
+      return 0;
+    }
+    const position = now_playing.started_at !== undefined && now_playing.started_at !== null
+      ? now / 1000 - now_playing.started_at
+      : now_playing.position ?? 0;
+    return Math.max(0, now_playing.time - position);
+  }
+
+  // Seconds until each entry starts, inexact when a song before it has an unknown length
+  function queue_etas(): Map<number, [number, boolean]> {
+    const etas = new Map<number, [number, boolean]>();
+    const times = new Map(recv.map((entry) => [entry.id, entry.time]));
+    let eta = time_left();
+    let exact = !now_playing || !!now_playing.time;
+    for (const id of play_order) {
+      etas.set(id, [eta, exact]);
+      const time = times.get(id);
+      if (time) {
+        eta += time;
       } else {
-        time = `${minute}:${second}`;
+        exact = false;
       }
     }
+    return etas;
+  }
+  const etas = queue_etas();
+
+  function gen_queue_entry(item: ListEntry) {
+    const time = item.time ? format_duration(item.time) : null;
+    const item_eta = item.id !== undefined ? etas.get(item.id) : undefined;
+    const eta = item_eta !== undefined
+      ? `in ${item_eta[1] ? "" : "~"}${format_duration(item_eta[0])}` : null;
+
+    const failed = item.state == "failed" || item.state == "retry_pending";
+
+    let primary = item.fetched ? item.title : "Fetching...";
+    if (item.state == "failed") {
+      primary = `Failed: ${item.error}`;
+    } else if (item.state == "retry_pending") {
+      primary = `Retrying... (${item.error})`;
+    }
 
     return <>
       <ListItem
         secondaryAction={
-          <IconButton edge="end" aria-label="copy link"
-            onClick={() => {
-              copyToClipboard(item.url);
-              display_snackbar("Link copied!");
-            }}>
-            <Link />
-          </IconButton>
+          failed ?
+            <>
+              <IconButton aria-label="retry"
+                onClick={() => on_queue_entry_action("retry", item.id!)}>
+                <Replay />
+              </IconButton>
+              <IconButton edge="end" aria-label="dismiss"
+                onClick={() => on_queue_entry_action("dismiss", item.id!)}>
+                <Close />
+              </IconButton>
+            </>
+            : item.url ?
+            <IconButton edge="end" aria-label="copy link"
+              onClick={() => {
+                copyToClipboard(item.url!);
+                display_snackbar("Link copied!");
+              }}>
+              <Link />
+            </IconButton>
+            : null
         }
       >
         <ListItemText
-          primary={item.fetched ? item.title : "Fetching..."}
-          secondary={item.fetched ? time : (item.title ? item.title : item.url)}
+          primary={primary}
+          secondary={item.fetched
+            ? [time, item.source, item.requester?.nickname, eta].filter(Boolean).join(" · ")
+            : (item.title ? item.title : item.url)}
         />
       </ListItem>
       <Divider />
@@ -203,6 +426,29 @@ function App() {
             onButton={on_player_button}
             onVolumeSlider={on_volume_slider}
           />
+          {schedule ?
+            <Typography variant="body2" color="text.secondary">
+              {[
+                `${schedule.name} until ${schedule.to.slice(0, 5)}`,
+                schedule.pause ? "paused" : null,
+                schedule.max_volume !== undefined && schedule.max_volume !== null
+                  ? `volume up to ${Math.round(schedule.max_volume * 100)}%` : null,
+              ].filter(Boolean).join(" · ")}
+            </Typography>
+            : null}
+          <form onSubmit={event => { event.preventDefault(); on_nickname_submit(); }}>
+            <TextField
+              fullWidth
+              label="Nickname"
+              variant="filled"
+              autoComplete="off"
+              margin="normal"
+              value={nickname}
+              onChange={(event: React.ChangeEvent<HTMLInputElement>) => {
+                setNickname(event.target.value);
+              }}
+            />
+          </form>
           <form onSubmit={event => { event.preventDefault(); on_yt_submit(); }}>
             <TextField
               fullWidth
@@ -220,8 +466,54 @@ function App() {
           <List>
             <ListSubheader>Now Playing</ListSubheader>
             {now_playing ? gen_queue_entry(now_playing) : null}
-            <ListSubheader>Queue</ListSubheader>
+            {now_playing ?
+              <Reactions counts={reactions} onReact={on_reaction} />
+              : null}
+            <ListSubheader>
+              Queue
+              {recv.length > 0
+                ? ` · ${recv.length} songs, ${total_time[1] ? "" : "~"}${format_duration(time_left() + total_time[0])}`
+                : null}
+              <IconButton aria-label="export queue" onClick={on_export}>
+                <Download />
+              </IconButton>
+              <IconButton aria-label="import playlist" component="label">
+                <Upload />
+                <input
+                  hidden
+                  type="file"
+                  accept=".m3u,.m3u8,.json,.txt"
+                  onChange={(event: React.ChangeEvent<HTMLInputElement>) => {
+                    const file = event.target.files?.[0];
+                    if (file) on_import(file);
+                    event.target.value = "";
+                  }}
+                />
+              </IconButton>
+            </ListSubheader>
             {recv.map(gen_queue_entry)}
+            {playlists.length > 0 ?
+              <ListSubheader>Playlists</ListSubheader>
+              : null}
+            {playlists.map(playlist =>
+              <ListItem
+                key={playlist.id}
+                secondaryAction={
+                  <IconButton edge="end" aria-label="enqueue playlist"
+                    onClick={() => on_queue_entry_action("playlist_enqueue", playlist.id)}>
+                    <PlaylistAdd />
+                  </IconButton>
+                }
+              >
+                <ListItemText
+                  primary={playlist.name}
+                  secondary={[
+                    `${playlist.songs.length} songs`,
+                    playlist.id == fallback ? "plays when the queue is empty" : null,
+                  ].filter(Boolean).join(" · ")}
+                />
+              </ListItem>
+            )}
           </List>
         </Container>
         <CustomSnackbar message={snackbar_message} key={snackbar_key} />
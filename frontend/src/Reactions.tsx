@@ -0,0 +1,33 @@
+import { Chip, Stack } from "@mui/material";
+
+// Same names as the backend
+const EMOJI: Array<[string, string]> = [
+  ["like", "👍"],
+  ["dislike", "👎"],
+  ["love", "❤️"],
+  ["fire", "🔥"],
+  ["laugh", "😂"],
+  ["sleep", "😴"],
+];
+
+type ReactionsProps = {
+  counts: Record<string, number>,
+  onReact: (reaction: string) => void,
+};
+
+function Reactions(props: ReactionsProps) {
+  return (
+    <Stack direction="row" spacing={1} sx={{ px: 2, pb: 1 }}>
+      {EMOJI.map(([reaction, emoji]) =>
+        <Chip
+          key={reaction}
+          label={props.counts[reaction] ? `${emoji} ${props.counts[reaction]}` : emoji}
+          variant={props.counts[reaction] ? "filled" : "outlined"}
+          onClick={() => props.onReact(reaction)}
+        />
+      )}
+    </Stack>
+  );
+}
+
+export default Reactions;
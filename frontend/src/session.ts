@@ -1,6 +1,22 @@
 import { useEffect, useState } from "react";
 
-const SERVER_URL = "wss://pi.makereallabs.org/ws/";
+// Same host as the page by default, so the backend can serve the frontend itself
+const SERVER_URL = import.meta.env.VITE_SERVER_URL
+  ?? `${location.protocol == "https:" ? "wss" : "ws"}://${location.host}/ws/`;
+
+const TOKEN_KEY = "browser_token";
+
+// Identifies this browser to the backend, kept across visits
+export function browserToken(): string {
+  let token = localStorage.getItem(TOKEN_KEY);
+  if (!token) {
+    // crypto.randomUUID needs a secure context (https), getRandomValues doesn't
+    const bytes = crypto.getRandomValues(new Uint8Array(16));
+    token = Array.from(bytes, b => b.toString(16).padStart(2, "0")).join("");
+    localStorage.setItem(TOKEN_KEY, token);
+  }
+  return token;
+}
 
 type OpenHandler = (ev: Event) => void;
 type ErrorHandler = (ev: Event) => void;